@@ -1,40 +1,12 @@
 use candid::{ CandidType, Principal };
 use ic_cdk::update;
-use rapidhash::RapidHasher;
-use std::{ cell::RefCell, hash::Hasher };
-use serde::{ Deserialize, Serialize };
+use std::cell::RefCell;
+
+pub use windoge_protocol::{ Block, BlockHeader, Hash, Stats, Transaction };
 
 const LEDGER_ID: &str = "hx36f-waaaa-aaaai-aq32q-cai";
 const CHUNK_SIZE: u64 = 200000; // 200k hashes
 
-type Hash = u128;
-
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
-struct Transaction {
-    sender: Principal,
-    recipient: Principal,
-    amount: u64,
-    timestamp: u64,
-}
-
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
-pub struct Block {
-    pub header: BlockHeader,
-    transactions: Vec<Transaction>,
-    nonce: u128,
-    hash: Hash,
-}
-
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
-pub struct BlockHeader {
-    version: u32,
-    pub height: u64,
-    prev_hash: Hash,
-    merkle_root: Hash,
-    timestamp: u64,
-    difficulty: u32,
-}
-
 #[update(hidden = true)]
 pub async fn find_solution() {
     if
@@ -54,9 +26,10 @@ pub async fn find_solution() {
     for i in 0..CHUNK_SIZE {
         block.nonce = xorshift_random(seed as u128, i as u128, miner_id as u128);
 
-        let hash = calculate_hash(&block);
+        let hash = block.calculate_hash();
 
-        if hash.leading_zeros() >= block.header.difficulty {
+        if block.meets_target(hash) {
+            block.hash = hash;
             if let Err(err) = submit_solution(block.clone()).await {
                 ic_cdk::println!("Error submitting solution: {:?}", err);
             } else {
@@ -110,14 +83,6 @@ fn update_mining_stats(is_mining: bool) {
     });
 }
 
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
-struct Stats {
-    cycles_burned: u64,
-    timestamp: u64,
-    solve_time: u64,
-    miner: Principal,
-}
-
 async fn submit_solution(block: Block) -> Result<(), String> {
     let ledger_id = read_state(|s| s.ledger_id);
     let start_time = read_state(|s| s.mining_start_time);
@@ -143,28 +108,6 @@ async fn submit_solution(block: Block) -> Result<(), String> {
     }
 }
 
-fn calculate_hash(block: &Block) -> Hash {
-    let mut hasher = RapidHasher::new(0);
-    let mut data = Vec::new();
-
-    data.extend_from_slice(&block.header.version.to_le_bytes());
-    data.extend_from_slice(&block.header.prev_hash.to_le_bytes());
-    data.extend_from_slice(&block.header.merkle_root.to_le_bytes());
-    data.extend_from_slice(&block.header.timestamp.to_le_bytes());
-    data.extend_from_slice(&block.nonce.to_le_bytes());
-
-    hasher.write(&data);
-    let hash64 = hasher.finish();
-
-    let hash128_high = {
-        let mut hasher = RapidHasher::new(hash64);
-        hasher.write(&hash64.to_le_bytes());
-        hasher.finish()
-    };
-
-    ((hash128_high as u128) << 64) | (hash64 as u128)
-}
-
 fn xorshift_random(mut seed1: u128, mut seed2: u128, mut seed3: u128) -> u128 {
     seed1 ^= seed1 << 13;
     seed1 ^= seed1 >> 17;
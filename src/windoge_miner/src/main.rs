@@ -121,7 +121,8 @@ async fn update_block() -> Result<(), String> {
         Ok(res) => {
             let res_block = Decode!(&res, Option<Block>).map_err(|e| format!("{:?}", e))?;
             ic_cdk::println!("Updating the block...");
-            if let Some(block) = res_block {
+            if let Some(mut block) = res_block {
+                block.set_coinbase_recipient(read_state(|s| s.owner));
                 let res_current_block = read_state(|s| s.current_block.clone());
                 if let Some(current_block) = res_current_block {
                     if current_block != block {
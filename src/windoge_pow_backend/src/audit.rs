@@ -0,0 +1,301 @@
+//! Replays `CHAIN` from genesis and the credited deposits to recompute every
+//! balance and the total supply, then compares the result with
+//! `ACCOUNT_TO_BALANCE`. Deposits are replayed in chain order, before the
+//! first block accepted after they were credited. The work is split into
+//! chunks so that each timer tick stays within the instruction limit.
+
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::{ BTreeMap, BTreeSet, VecDeque };
+use std::ops::Bound;
+use crate::memory::{
+    all_deposits,
+    balances_after,
+    block_count,
+    deposit_count,
+    get_accepted_at,
+    get_block,
+    get_miner_owner,
+    get_stat,
+    update_audit_report,
+    Account,
+    Block,
+};
+use crate::deposits::Deposit;
+use crate::rewards_at;
+
+/// Blocks replayed per tick.
+pub const AUDIT_BLOCKS_PER_TICK: u64 = 500;
+/// Stored balances compared per tick.
+pub const AUDIT_ACCOUNTS_PER_TICK: usize = 1_000;
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BalanceDivergence {
+    pub principal: Principal,
+    #[serde(default)]
+    pub subaccount: Option<ByteBuf>,
+    pub replayed: u64,
+    pub stored: u64,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct AuditReport {
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub blocks_replayed: u64,
+    /// Block rewards without fees, including those credited before blocks
+    /// carried a coinbase.
+    pub minted: u64,
+    /// Transfers to the backend, which leave the chain as BIL on the ledger.
+    pub withdrawn: u64,
+    /// Deposits from the BIL ledger, credited outside of blocks.
+    #[serde(default)]
+    pub deposited: u64,
+    /// Sum of the replayed balances.
+    pub replayed_supply: u64,
+    /// Sum of the balances in `ACCOUNT_TO_BALANCE`.
+    pub stored_supply: u64,
+    /// Rewards of blocks without a coinbase whose miner owner could not be
+    /// resolved from the stats log. They are counted as minted but credited
+    /// to nobody.
+    pub unattributed_rewards: u64,
+    pub divergences: Vec<BalanceDivergence>,
+}
+
+enum Phase {
+    Replay,
+    Compare {
+        after: Option<Account>,
+    },
+}
+
+struct Audit {
+    phase: Phase,
+    next_height: u64,
+    balances: BTreeMap<Account, u64>,
+    /// Deposits not replayed yet, oldest first.
+    deposits: VecDeque<Deposit>,
+    /// Ledger indices of the replayed deposits.
+    replayed_deposits: BTreeSet<u64>,
+    /// Deposits stored when `deposits` was last refreshed.
+    deposits_seen: u64,
+    report: AuditReport,
+}
+
+thread_local! {
+    static AUDIT: RefCell<Option<Audit>> = RefCell::default();
+}
+
+pub fn is_running() -> bool {
+    AUDIT.with(|a| a.borrow().is_some())
+}
+
+/// Progress of the running audit, if any.
+pub fn progress() -> Option<AuditReport> {
+    AUDIT.with(|a| a.borrow().as_ref().map(|audit| audit.report.clone()))
+}
+
+pub fn start(now: u64) -> Result<(), String> {
+    if is_running() {
+        return Err("audit already running".to_string());
+    }
+
+    AUDIT.with(|a| {
+        *a.borrow_mut() = Some(Audit {
+            phase: Phase::Replay,
+            // genesis carries no transactions
+            next_height: 1,
+            balances: BTreeMap::new(),
+            deposits: VecDeque::new(),
+            replayed_deposits: BTreeSet::new(),
+            deposits_seen: 0,
+            report: AuditReport {
+                started_at: now,
+                ..Default::default()
+            },
+        });
+    });
+
+    Ok(())
+}
+
+/// Runs one chunk of the audit. Returns whether more chunks are needed.
+pub fn step(now: u64) -> bool {
+    AUDIT.with(|a| {
+        let mut slot = a.borrow_mut();
+        let Some(audit) = slot.as_mut() else {
+            return false;
+        };
+
+        if audit.step() {
+            return true;
+        }
+
+        let mut report = std::mem::take(&mut audit.report);
+        report.finished_at = Some(now);
+        let _ = update_audit_report(report);
+        *slot = None;
+        false
+    })
+}
+
+impl Audit {
+    fn step(&mut self) -> bool {
+        // blocks accepted while comparing change stored balances that were
+        // already checked, so the comparison starts over after replaying them
+        if self.next_height < block_count() {
+            self.replay_chunk();
+            self.phase = Phase::Replay;
+            return true;
+        }
+
+        let after = match &self.phase {
+            Phase::Replay => {
+                // every deposit left was credited after the latest block
+                self.refresh_deposits();
+                self.replay_deposits_before(u64::MAX);
+                self.report.replayed_supply = self.balances.values().sum();
+                self.report.stored_supply = 0;
+                self.report.divergences.clear();
+                None
+            }
+            Phase::Compare { after } => after.clone(),
+        };
+
+        let stored: BTreeMap<Account, u64> = balances_after(
+            after.clone(),
+            AUDIT_ACCOUNTS_PER_TICK
+        ).into_iter().collect();
+        let last = match stored.last_key_value() {
+            Some((account, _)) if stored.len() == AUDIT_ACCOUNTS_PER_TICK => Some(account.clone()),
+            _ => None,
+        };
+
+        // walk the replayed balances over the same key range, so accounts
+        // missing from the stable map are caught as well
+        let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let upper = last.clone().map_or(Bound::Unbounded, Bound::Included);
+        for (account, replayed) in self.balances.range((lower, upper)) {
+            if *replayed > 0 && !stored.contains_key(account) {
+                self.report.divergences.push(BalanceDivergence {
+                    principal: account.owner,
+                    subaccount: account.subaccount.clone(),
+                    replayed: *replayed,
+                    stored: 0,
+                });
+            }
+        }
+
+        for (account, balance) in &stored {
+            self.report.stored_supply += balance;
+
+            let replayed = self.balances.get(account).copied().unwrap_or(0);
+            if replayed != *balance {
+                self.report.divergences.push(BalanceDivergence {
+                    principal: account.owner,
+                    subaccount: account.subaccount.clone(),
+                    replayed,
+                    stored: *balance,
+                });
+            }
+        }
+
+        match last {
+            Some(last) => {
+                self.phase = Phase::Compare { after: Some(last) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn replay_chunk(&mut self) {
+        self.refresh_deposits();
+
+        let end = block_count().min(self.next_height + AUDIT_BLOCKS_PER_TICK);
+        for height in self.next_height..end {
+            if let Some(block) = get_block(height) {
+                let accepted_at = get_accepted_at(height).unwrap_or(block.header.timestamp);
+                self.replay_deposits_before(accepted_at);
+                self.apply_block(&block);
+                self.report.blocks_replayed += 1;
+            }
+        }
+        self.next_height = end;
+    }
+
+    /// Queues the deposits credited since the last refresh.
+    fn refresh_deposits(&mut self) {
+        let count = deposit_count();
+        if count == self.deposits_seen {
+            return;
+        }
+
+        let mut deposits: Vec<Deposit> = all_deposits()
+            .into_iter()
+            .filter(|deposit| !self.replayed_deposits.contains(&deposit.block_index))
+            .collect();
+        deposits.sort_by_key(|deposit| (deposit.credited_at, deposit.block_index));
+        self.deposits = deposits.into();
+        self.deposits_seen = count;
+    }
+
+    /// Credits the queued deposits credited before `time`.
+    fn replay_deposits_before(&mut self, time: u64) {
+        while self.deposits.front().is_some_and(|deposit| deposit.credited_at < time) {
+            let deposit = self.deposits.pop_front().unwrap();
+            self.report.deposited += deposit.amount;
+            self.credit(&deposit.account, deposit.amount);
+            self.replayed_deposits.insert(deposit.block_index);
+        }
+    }
+
+    /// Applies a block the same way `submit_solution` does.
+    fn apply_block(&mut self, block: &Block) {
+        let height = block.header.height;
+
+        if block.coinbase().is_none() {
+            // before the coinbase the reward went to the owner of the miner
+            // that submitted the block, read after the block was appended
+            let reward = rewards_at(height + 1);
+            self.report.minted += reward;
+
+            let owner = get_stat(height - 1).and_then(|stats| get_miner_owner(stats.miner));
+            match owner {
+                Some(owner) => self.credit(&Account::from(owner), reward),
+                None => {
+                    self.report.unattributed_rewards += reward;
+                }
+            }
+        }
+
+        for tx in &block.transactions {
+            if tx.is_coinbase() {
+                self.report.minted += tx.amount.saturating_sub(block.fees());
+                self.credit(&tx.to(), tx.amount);
+                continue;
+            }
+
+            let from = tx.from();
+            self.debit(&from, tx.fee);
+            if tx.recipient == ic_cdk::id() {
+                self.report.withdrawn += tx.amount;
+                self.debit(&from, tx.amount);
+            } else {
+                self.credit(&tx.to(), tx.amount);
+                self.debit(&from, tx.amount);
+            }
+        }
+    }
+
+    fn credit(&mut self, account: &Account, amount: u64) {
+        *self.balances.entry(account.clone()).or_insert(0) += amount;
+    }
+
+    fn debit(&mut self, account: &Account, amount: u64) {
+        let balance = self.balances.entry(account.clone()).or_insert(0);
+        *balance = balance.saturating_sub(amount);
+    }
+}
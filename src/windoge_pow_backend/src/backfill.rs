@@ -0,0 +1,86 @@
+//! Walk over the blocks stored before an upgrade, seeding the supply totals
+//! and the history indexes of canisters upgraded from before they were kept.
+//! Like the audit, the walk is split into chunks run on timer ticks, so a
+//! long chain can't push the upgrade over the instruction limit. Its position
+//! is kept in stable memory, and an upgrade in the middle of it resumes where
+//! it stopped. Blocks accepted meanwhile add to the totals and are indexed as
+//! usual.
+
+use candid::Principal;
+use serde::{ Deserialize, Serialize };
+use crate::memory::{
+    add_minted,
+    add_withdrawn,
+    backfill,
+    block_count,
+    get_block,
+    update_backfill,
+};
+use crate::history::index_block;
+use crate::supply::block_totals;
+
+/// Blocks walked per tick.
+pub const BACKFILL_BLOCKS_PER_TICK: u64 = 500;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Backfill {
+    /// Next block to walk.
+    pub next_height: u64,
+    /// Height of the first block accepted after the walk started. Later
+    /// blocks were accounted for when they were accepted.
+    pub end: u64,
+    /// Whether the walk adds to the minted and withdrawn totals.
+    pub supply: bool,
+    /// Whether the walk indexes the transactions of the blocks.
+    #[serde(default)]
+    pub history: bool,
+}
+
+/// Starts a walk over the stored blocks, unless one is running already.
+/// The caller zeroes the totals it seeds first, so accepted blocks can add to
+/// them right away.
+pub fn start(supply: bool, history: bool) {
+    if !(supply || history) || is_running() {
+        return;
+    }
+
+    let _ = update_backfill(
+        Some(Backfill {
+            next_height: 1,
+            end: block_count(),
+            supply,
+            history,
+        })
+    );
+}
+
+pub fn is_running() -> bool {
+    backfill().is_some()
+}
+
+/// Walks the next chunk of blocks. Returns whether there is more to do.
+pub fn step(backend: Principal) -> bool {
+    let Some(mut walk) = backfill() else {
+        return false;
+    };
+
+    let end = walk.end.min(walk.next_height.saturating_add(BACKFILL_BLOCKS_PER_TICK));
+    for height in walk.next_height..end {
+        let Some(block) = get_block(height) else {
+            continue;
+        };
+        if walk.supply {
+            let (minted, withdrawn) = block_totals(&block, backend);
+            let _ = add_minted(minted);
+            let _ = add_withdrawn(withdrawn);
+        }
+        if walk.history {
+            index_block(&block);
+        }
+    }
+    walk.next_height = end;
+
+    let more = walk.next_height < walk.end;
+    let _ = update_backfill(more.then_some(walk));
+    more
+}
@@ -0,0 +1,350 @@
+//! Deposits of BIL from the ledger onto the chain. Every on-chain account has
+//! its own deposit subaccount of the backend on the BIL ledger. A transfer to
+//! it is burned and credited once the owner notifies the backend of the
+//! ledger block; an ICRC-2 approval is pulled straight into the minting
+//! account, which burns it, and credited right away. Deposits are burned
+//! because withdrawals mint fresh BIL, so the ledger supply only holds what
+//! is not on chain. Each ledger block is credited at most once.
+//!
+//! A deposit is recorded as pending before the ledger call that burns it,
+//! with the `created_at_time` of the first attempt. A call whose outcome is
+//! unknown leaves it pending, and it is sent again with the same arguments
+//! until the ledger settles it, so a burn that went through unnoticed comes
+//! back as a duplicate instead of being lost or charged twice.
+
+use candid::{ CandidType, Nat, Principal };
+use num_traits::ToPrimitive;
+use serde::{ Deserialize, Serialize };
+use serde_bytes::ByteBuf;
+use sha2::{ Digest, Sha256 };
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use crate::ledger::{
+    get_transaction,
+    icrc1_transfer,
+    icrc2_transfer_from,
+    TransferArg,
+    TransferError,
+    TransferFromArgs,
+    TransferFromError,
+};
+use crate::memory::{
+    add_balance,
+    add_deposited,
+    get_deposit,
+    get_pending_deposit,
+    insert_deposit,
+    insert_pending_deposit,
+    pending_deposits,
+    remove_pending_deposit,
+    Account,
+};
+use crate::queue::{ InFlight, RetryQueue };
+use crate::BIL_LEDGER_ID;
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Deposit {
+    /// Index of the transfer on the BIL ledger.
+    pub block_index: u64,
+    /// On-chain account credited.
+    pub account: Account,
+    pub amount: u64,
+    pub credited_at: u64,
+    /// Index of the burn of the deposit on the BIL ledger. `None` for
+    /// deposits credited before deposits were burned.
+    #[serde(default)]
+    pub burn_index: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ClaimDepositArgs {
+    pub amount: u64,
+    /// Subaccount of the caller on the BIL ledger that approved the backend.
+    pub from_subaccount: Option<ByteBuf>,
+    /// Subaccount of the caller on chain to credit.
+    pub to_subaccount: Option<ByteBuf>,
+}
+
+/// Where the BIL of a pending deposit is taken from.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum DepositSource {
+    /// Ledger transfer `block_index` to a deposit account, burned from there.
+    Transfer {
+        block_index: u64,
+    },
+    /// ICRC-2 approval of `from`, pulled into the minting account. A payer
+    /// has one pending claim at a time.
+    Approval {
+        from: Account,
+    },
+}
+
+/// Deposit whose ledger call hasn't settled yet.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PendingDeposit {
+    pub source: DepositSource,
+    /// On-chain account to credit.
+    pub account: Account,
+    pub amount: u64,
+    /// Sent on every attempt, so the ledger deduplicates them.
+    pub created_at_time: u64,
+}
+
+/// Outcome of the ledger call of a pending deposit.
+enum LedgerOutcome {
+    /// The ledger took the BIL in block `index`.
+    Settled(u64),
+    /// The call may or may not have gone through.
+    Unknown(String),
+    Rejected(String),
+}
+
+/// Domain separator of the deposit subaccounts, length-prefixed.
+const DEPOSIT_DOMAIN: &[u8] = b"\x0fwindoge-deposit";
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<DepositSource>> = RefCell::default();
+}
+
+/// Subaccount of the backend on the BIL ledger that takes the deposits of
+/// `account`: the SHA-256 of a domain separator and the length-prefixed
+/// owner and subaccount of `account`.
+pub fn deposit_subaccount(account: &Account) -> ByteBuf {
+    let subaccount: &[u8] = account.subaccount.as_deref().map_or(&[], |s| s.as_slice());
+    let owner = account.owner.as_slice();
+
+    let mut hasher = Sha256::new();
+    hasher.update(DEPOSIT_DOMAIN);
+    hasher.update([owner.len() as u8]);
+    hasher.update(owner);
+    hasher.update([subaccount.len() as u8]);
+    hasher.update(subaccount);
+    ByteBuf::from(hasher.finalize().to_vec())
+}
+
+/// Ledger account of the backend that takes the deposits of `account`.
+pub fn deposit_account(account: &Account) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(deposit_subaccount(account)),
+    }
+}
+
+/// Minting account of the BIL ledger, the default account of the backend.
+fn minting_account() -> Account {
+    Account::from(ic_cdk::id())
+}
+
+/// Burns and credits the ledger transfer `block_index` to `account`. The
+/// transfer must go to the deposit account of `account`.
+pub async fn notify(account: Account, block_index: u64, now: u64) -> Result<Deposit, String> {
+    if get_deposit(block_index).is_some() {
+        return Err("deposit already processed".to_string());
+    }
+
+    let source = DepositSource::Transfer { block_index };
+    let pending = match get_pending_deposit(&source) {
+        Some(pending) => pending,
+        None => {
+            let amount = verify_transfer(&account, block_index).await?;
+            // another call may have recorded it during the await
+            get_pending_deposit(&source).unwrap_or_else(|| {
+                let pending = PendingDeposit {
+                    source: source.clone(),
+                    account: account.clone(),
+                    amount,
+                    created_at_time: now,
+                };
+                insert_pending_deposit(pending.clone());
+                pending
+            })
+        }
+    };
+    if pending.account != account {
+        return Err("transfer not to the deposit account".to_string());
+    }
+
+    process(source).await
+}
+
+/// Amount of the ledger transfer `block_index` to the deposit account of
+/// `account`.
+async fn verify_transfer(account: &Account, block_index: u64) -> Result<u64, String> {
+    let bil_ledger = Principal::from_text(BIL_LEDGER_ID).unwrap();
+    let transaction = get_transaction(bil_ledger, Nat::from(block_index)).await?;
+    let transfer = transaction.transfer.ok_or("expected transfer".to_string())?;
+
+    let expected = deposit_account(account);
+    if transfer.to.owner != expected.owner || transfer.to.subaccount != expected.subaccount {
+        return Err("transfer not to the deposit account".to_string());
+    }
+    let amount = transfer.amount.0.to_u64().ok_or("amount overflow".to_string())?;
+    if amount == 0 {
+        return Err("amount must be greater than 0".to_string());
+    }
+    Ok(amount)
+}
+
+/// Pulls `amount` the caller approved from `from` into the minting account,
+/// which burns it, and credits it to `account`. A claim from `from` that is
+/// still pending is only sent again, and must match.
+pub async fn claim(
+    from: Account,
+    account: Account,
+    amount: u64,
+    now: u64
+) -> Result<Deposit, String> {
+    if amount == 0 {
+        return Err("amount must be greater than 0".to_string());
+    }
+
+    let source = DepositSource::Approval { from };
+    match get_pending_deposit(&source) {
+        Some(pending) if pending.account != account || pending.amount != amount => {
+            return Err("another claim from this account is pending".to_string());
+        }
+        Some(_) => {}
+        None => {
+            insert_pending_deposit(PendingDeposit {
+                source: source.clone(),
+                account,
+                amount,
+                created_at_time: now,
+            });
+        }
+    }
+
+    process(source).await
+}
+
+/// Sends the ledger call of the pending deposit `source`, and credits it once
+/// the ledger took the BIL. A rejected deposit is dropped, one with an
+/// unknown outcome stays pending.
+pub async fn process(source: DepositSource) -> Result<Deposit, String> {
+    let Some(_in_flight) = InFlight::acquire(&IN_FLIGHT, source.clone()) else {
+        return Err("deposit is being processed".to_string());
+    };
+    let pending = get_pending_deposit(&source).ok_or("deposit is not pending".to_string())?;
+
+    match send(&pending).await {
+        LedgerOutcome::Settled(index) => {
+            remove_pending_deposit(&source);
+            let block_index = match source {
+                DepositSource::Transfer { block_index } => block_index,
+                DepositSource::Approval { .. } => index,
+            };
+            credit(pending.account, block_index, pending.amount, ic_cdk::api::time(), index)
+        }
+        LedgerOutcome::Unknown(error) => {
+            Err(format!("{}, the deposit stays pending and is retried", error))
+        }
+        LedgerOutcome::Rejected(error) => {
+            remove_pending_deposit(&source);
+            Err(error)
+        }
+    }
+}
+
+async fn send(pending: &PendingDeposit) -> LedgerOutcome {
+    let bil_ledger = Principal::from_text(BIL_LEDGER_ID).unwrap();
+    match &pending.source {
+        DepositSource::Transfer { block_index } => {
+            // a transfer to the minting account burns it
+            let burn = TransferArg {
+                to: minting_account(),
+                fee: None,
+                memo: Some(ByteBuf::from(block_index.to_le_bytes().to_vec())),
+                from_subaccount: Some(deposit_subaccount(&pending.account)),
+                created_at_time: Some(pending.created_at_time),
+                amount: pending.amount.into(),
+            };
+            match icrc1_transfer(burn, bil_ledger).await {
+                Ok(Ok(index)) | Ok(Err(TransferError::Duplicate { duplicate_of: index })) => {
+                    settled(index)
+                }
+                Ok(Err(TransferError::TemporarilyUnavailable)) => {
+                    LedgerOutcome::Unknown("BIL ledger unavailable".to_string())
+                }
+                Ok(Err(e)) => {
+                    LedgerOutcome::Rejected(format!("burning the deposit failed: {:?}", e))
+                }
+                Err(e) => LedgerOutcome::Unknown(e),
+            }
+        }
+        DepositSource::Approval { from } => {
+            let args = TransferFromArgs {
+                spender_subaccount: None,
+                from: from.clone(),
+                to: minting_account(),
+                amount: pending.amount.into(),
+                fee: None,
+                memo: None,
+                created_at_time: Some(pending.created_at_time),
+            };
+            match icrc2_transfer_from(args, bil_ledger).await {
+                Ok(Ok(index)) | Ok(Err(TransferFromError::Duplicate { duplicate_of: index })) => {
+                    settled(index)
+                }
+                Ok(Err(TransferFromError::TemporarilyUnavailable)) => {
+                    LedgerOutcome::Unknown("BIL ledger unavailable".to_string())
+                }
+                Ok(Err(e)) => LedgerOutcome::Rejected(format!("{:?}", e)),
+                Err(e) => LedgerOutcome::Unknown(e),
+            }
+        }
+    }
+}
+
+fn settled(index: Nat) -> LedgerOutcome {
+    match index.0.to_u64() {
+        Some(index) => LedgerOutcome::Settled(index),
+        None => LedgerOutcome::Rejected("block index overflow".to_string()),
+    }
+}
+
+fn credit(
+    account: Account,
+    block_index: u64,
+    amount: u64,
+    now: u64,
+    burn_index: u64
+) -> Result<Deposit, String> {
+    // a duplicate reply may name a block that was credited already
+    if get_deposit(block_index).is_some() {
+        return Err("deposit already processed".to_string());
+    }
+
+    let deposit = Deposit {
+        block_index,
+        account,
+        amount,
+        credited_at: now,
+        burn_index: Some(burn_index),
+    };
+    insert_deposit(deposit.clone());
+    add_balance(&deposit.account, amount);
+    let _ = add_deposited(amount);
+
+    ic_cdk::println!("Deposit {} credited", block_index);
+    Ok(deposit)
+}
+
+/// Deposits whose ledger call hasn't settled yet.
+pub struct PendingDeposits;
+
+impl RetryQueue for PendingDeposits {
+    type Key = DepositSource;
+
+    fn queued() -> Vec<DepositSource> {
+        pending_deposits()
+            .into_iter()
+            .map(|pending| pending.source)
+            .collect()
+    }
+
+    async fn process(source: DepositSource) {
+        if let Err(e) = process(source).await {
+            ic_cdk::println!("Pending deposit not credited: {}", e);
+        }
+    }
+}
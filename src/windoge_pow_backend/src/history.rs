@@ -0,0 +1,124 @@
+use candid::CandidType;
+use serde::{ Deserialize, Serialize };
+use serde_bytes::ByteBuf;
+use crate::memory::{
+    account_tx_locations,
+    block_count,
+    get_block,
+    get_dropped,
+    get_withdrawal,
+    get_tx_height,
+    insert_account_tx,
+    insert_memo_index,
+    insert_tx_height,
+    memo_locations,
+    Account,
+    Block,
+    Hash,
+    Transaction,
+};
+use crate::mempool::{ has_id, in_template, DropReason };
+use crate::withdrawals::WithdrawalStatus;
+use crate::State;
+
+/// Most transactions returned by one history query.
+pub const MAX_HISTORY_PAGE: usize = 100;
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BlockTransaction {
+    pub height: u64,
+    pub transaction: Transaction,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum TransactionStatus {
+    /// Waiting in the mempool.
+    Pending,
+    /// Part of the block template miners are working on.
+    InTemplate,
+    Confirmed {
+        height: u64,
+        /// Blocks on top of the including block, the including one counted.
+        confirmations: u64,
+    },
+    /// Mined withdrawal whose BIL could not be minted on the ledger.
+    WithdrawalFailed {
+        height: u64,
+        error: String,
+    },
+    /// Left the mempool without being mined.
+    Dropped {
+        reason: DropReason,
+    },
+}
+
+/// Adds the transactions of an accepted block to the history indexes. A
+/// transaction is listed for the account it debits, unless it's a coinbase,
+/// and for the account it credits, withdrawals to the backend included.
+/// Batches are found by their id as well. The batch and memo of a coinbase
+/// aren't indexed, as the miner rather than a sender would choose them.
+pub fn index_block(block: &Block) {
+    let height = block.header.height;
+    for (position, tx) in block.transactions.iter().enumerate() {
+        let position = position as u32;
+        insert_tx_height(tx.id(), height);
+        if !tx.is_coinbase() {
+            if let Some(batch) = tx.batch {
+                insert_tx_height(batch.id, height);
+            }
+            if let Some(memo) = &tx.memo {
+                insert_memo_index(memo.clone(), height, position);
+            }
+        }
+
+        let to = tx.to();
+        if !tx.is_coinbase() && tx.from() != to {
+            insert_account_tx(tx.from(), height, position);
+        }
+        insert_account_tx(to, height, position);
+    }
+}
+
+/// Status of the transaction or batch `tx_id`, `None` if it is unknown.
+pub fn transaction_status(state: &State, tx_id: Hash) -> Option<TransactionStatus> {
+    if let Some(height) = get_tx_height(tx_id) {
+        let withdrawal = get_withdrawal(tx_id).map(|w| w.status);
+        if let Some(WithdrawalStatus::Failed { error }) = withdrawal {
+            return Some(TransactionStatus::WithdrawalFailed { height, error });
+        }
+        let confirmations = block_count().saturating_sub(height);
+        return Some(TransactionStatus::Confirmed { height, confirmations });
+    }
+
+    if in_template(state, tx_id) {
+        return Some(TransactionStatus::InTemplate);
+    }
+    if state.mempool.iter().any(|tx| has_id(tx, tx_id)) {
+        return Some(TransactionStatus::Pending);
+    }
+    get_dropped(tx_id).map(|reason| TransactionStatus::Dropped { reason })
+}
+
+/// Mined transactions of `account`, newest first, skipping the `start` newest
+/// ones. At most `MAX_HISTORY_PAGE` are returned.
+pub fn account_transactions(account: &Account, start: u64, limit: u64) -> Vec<BlockTransaction> {
+    let limit = (limit as usize).min(MAX_HISTORY_PAGE);
+    account_tx_locations(account, start as usize, limit)
+        .into_iter()
+        .filter_map(|(height, position)| block_transaction(height, position))
+        .collect()
+}
+
+fn block_transaction(height: u64, position: u32) -> Option<BlockTransaction> {
+    let block = get_block(height)?;
+    let transaction = block.transactions.get(position as usize)?.clone();
+    Some(BlockTransaction { height, transaction })
+}
+
+/// Latest mined transactions carrying `memo`, newest first.
+pub fn transactions_with_memo(memo: &ByteBuf) -> Vec<BlockTransaction> {
+    memo_locations(memo, MAX_HISTORY_PAGE)
+        .into_iter()
+        .filter_map(|(height, position)| block_transaction(height, position))
+        .collect()
+}
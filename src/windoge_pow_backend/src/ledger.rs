@@ -0,0 +1,188 @@
+//! ICRC-1 and ICRC-2 calls to the ledgers the backend works with.
+
+use candid::utils::ArgumentEncoder;
+use candid::{ CandidType, Decode, Nat, Principal };
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use crate::memory::Account;
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferArg {
+    pub to: Account,
+    pub fee: Option<Nat>,
+    pub memo: Option<ByteBuf>,
+    pub from_subaccount: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    GenericError {
+        message: String,
+        error_code: Nat,
+    },
+    TemporarilyUnavailable,
+    BadBurn {
+        min_burn_amount: Nat,
+    },
+    Duplicate {
+        duplicate_of: Nat,
+    },
+    BadFee {
+        expected_fee: Nat,
+    },
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TooOld,
+    InsufficientFunds {
+        balance: Nat,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+enum TransferResult {
+    Ok(Nat),
+    Err(TransferError),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BurnArgs {
+    pub memo: Option<ByteBuf>,
+    pub from_subaccount: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<ByteBuf>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee {
+        expected_fee: Nat,
+    },
+    BadBurn {
+        min_burn_amount: Nat,
+    },
+    InsufficientFunds {
+        balance: Nat,
+    },
+    InsufficientAllowance {
+        allowance: Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    Duplicate {
+        duplicate_of: Nat,
+    },
+    TemporarilyUnavailable,
+    GenericError {
+        error_code: Nat,
+        message: String,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+enum TransferFromResult {
+    Ok(Nat),
+    Err(TransferFromError),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Burn {
+    pub from: Account,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Mint1 {
+    pub to: Account,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Transfer {
+    pub to: Account,
+    pub fee: Option<Nat>,
+    pub from: Account,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Transaction1 {
+    pub burn: Option<Burn>,
+    pub kind: String,
+    pub mint: Option<Mint1>,
+    pub timestamp: u64,
+    pub index: Nat,
+    pub transfer: Option<Transfer>,
+}
+
+async fn call<A, R>(token: Principal, method: &str, args: A) -> Result<R, String>
+    where A: ArgumentEncoder, R: CandidType + for<'de> Deserialize<'de>
+{
+    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
+        ::call_raw(token, method, candid::encode_args(args).unwrap(), 0).await
+        .map_err(|(code, msg)| (code as i32, msg));
+    match result {
+        Ok(res) => Decode!(&res, R).map_err(|e| format!("{:?}", e)),
+        Err((code, msg)) => Err(format!("Error {} ({}): {:?}", method, code, msg)),
+    }
+}
+
+/// Calls `icrc1_transfer` on `token`. The outer error means the call itself
+/// failed, the inner one that the ledger rejected the transfer.
+pub async fn icrc1_transfer(
+    transfer: TransferArg,
+    token: Principal
+) -> Result<Result<Nat, TransferError>, String> {
+    match call(token, "icrc1_transfer", (transfer,)).await? {
+        TransferResult::Ok(index) => Ok(Ok(index)),
+        TransferResult::Err(e) => Ok(Err(e)),
+    }
+}
+
+/// Calls `icrc2_transfer_from` on `token`, errors as in `icrc1_transfer`.
+pub async fn icrc2_transfer_from(
+    args: TransferFromArgs,
+    token: Principal
+) -> Result<Result<Nat, TransferFromError>, String> {
+    match call(token, "icrc2_transfer_from", (args,)).await? {
+        TransferFromResult::Ok(index) => Ok(Ok(index)),
+        TransferFromResult::Err(e) => Ok(Err(e)),
+    }
+}
+
+/// Calls `burn` on `token`, errors as in `icrc1_transfer`.
+pub async fn burn(args: BurnArgs, token: Principal) -> Result<Result<Nat, TransferError>, String> {
+    match call(token, "burn", (args,)).await? {
+        TransferResult::Ok(index) => Ok(Ok(index)),
+        TransferResult::Err(e) => Ok(Err(e)),
+    }
+}
+
+/// Transaction `index` of `token`.
+pub async fn get_transaction(token: Principal, index: Nat) -> Result<Transaction1, String> {
+    let transaction: Option<Transaction1> = call(token, "get_transaction", (index,)).await?;
+    let transaction = transaction.ok_or("Block not found".to_string())?;
+    ic_cdk::println!("transaction: {:?}", transaction.index);
+    Ok(transaction)
+}
@@ -1,22 +1,55 @@
 use candid::{ CandidType, Principal };
-use memory::{block_count, Block, Transaction};
+use memory::{block_count, Account, Block, Hash, Transaction};
+use mempool::MempoolConfig;
+use retarget::{ difficulty_to_target, RetargetConfig };
 use serde::{ Deserialize, Serialize };
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::{ BTreeMap, BTreeSet };
 
 const COINBASE_REWARDS: u64 = 60_000_000_000;
-const INIT_DIFFICULTY: u32 = 26;
+pub const INIT_DIFFICULTY: u32 = 26;
 pub const BLOCK_HALVING: u64 = 17_500;
 pub const SEC_NANOS: u64 = 1_000_000_000;
 pub const BIL_LEDGER_ID: &str = "ktra4-taaaa-aaaag-atveq-cai";
-
+pub const WINDOGE_LEDGER_ID: &str = "rh2pm-ryaaa-aaaan-qeniq-cai";
+pub const WINDOGE_MINER_CREATION_AMOUNT: u64 = 1500000000; // 15 Windoge98
+
+pub mod audit;
+pub mod backfill;
+pub mod deposits;
+pub mod history;
+pub mod ledger;
 pub mod memory;
+pub mod mempool;
 pub mod miner;
+pub mod payments;
+pub mod provisioning;
+pub mod queue;
+pub mod retarget;
+pub mod supply;
+pub mod validation;
+pub mod withdrawals;
 
 #[derive(Debug, Clone)]
 pub struct MinerWasm;
 
+#[derive(Clone, CandidType, Deserialize, Debug, Default)]
+pub struct InitArgs {
+    pub retarget: Option<RetargetConfig>,
+    pub empty_blocks: Option<EmptyBlockConfig>,
+    pub mempool: Option<MempoolConfig>,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct EmptyBlockConfig {
+    /// Publish a coinbase-only template when the mempool is empty.
+    pub enabled: bool,
+    /// Seconds to wait after the latest block was accepted before an empty
+    /// template is published.
+    pub min_interval_secs: u64,
+}
+
 pub fn miner_wasm() -> Cow<'static, [u8]> {
     Cow::Borrowed(include_bytes!(env!("MINER_WASM_PATH")))
 }
@@ -29,6 +62,14 @@ thread_local! {
 pub struct State {
     pub current_difficulty: u32,
 
+    pub current_target: Hash,
+
+    pub retarget: RetargetConfig,
+
+    pub empty_blocks: EmptyBlockConfig,
+
+    pub mempool_config: MempoolConfig,
+
     pub transaction_count: u64,
 
     pub block_height: u64,
@@ -51,9 +92,13 @@ pub struct State {
 
     pub miner_creation_transactions: BTreeSet<u64>,
 
+    /// Payments reserved by calls still processing them.
+    #[serde(default)]
+    pub reserved_payments: BTreeSet<u64>,
+
     pub mempool: Vec<Transaction>,
 
-    pub pending_balance: BTreeMap<Principal, u64>,
+    pub pending_balance: BTreeMap<Account, u64>,
 
     pub updated_miners: Vec<Principal>
 }
@@ -63,6 +108,14 @@ impl State {
         Self {
             current_difficulty: INIT_DIFFICULTY,
 
+            current_target: difficulty_to_target(INIT_DIFFICULTY as f64),
+
+            retarget: RetargetConfig::default(),
+
+            empty_blocks: EmptyBlockConfig::default(),
+
+            mempool_config: MempoolConfig::default(),
+
             transaction_count: 0,
 
             block_height: 0,
@@ -84,6 +137,8 @@ impl State {
 
             miner_creation_transactions: BTreeSet::default(),
 
+            reserved_payments: BTreeSet::default(),
+
             mempool: Vec::new(),
 
             pending_balance: BTreeMap::default(),
@@ -99,10 +154,15 @@ impl State {
     }
 
     pub fn current_rewards(&self) -> u64 {
-        COINBASE_REWARDS >> (block_count() / BLOCK_HALVING)
+        rewards_at(block_count())
     }
 }
 
+/// Coinbase reward of the block at `height`.
+pub fn rewards_at(height: u64) -> u64 {
+    COINBASE_REWARDS.checked_shr((height / BLOCK_HALVING) as u32).unwrap_or(0)
+}
+
 pub fn mutate_state<F, R>(f: F) -> R where F: FnOnce(&mut State) -> R {
     __STATE.with(|s| f(s.borrow_mut().as_mut().expect("State not initialized!")))
 }
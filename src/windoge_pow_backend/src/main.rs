@@ -1,81 +1,226 @@
-use std::hash::Hasher;
-use rapidhash::RapidHasher;
+use windoge_pow_backend::audit::{ self, AuditReport };
+use windoge_pow_backend::backfill;
 use windoge_pow_backend::memory::{
+    self,
+    account_tx_count,
     add_balance,
+    add_minted,
+    audit_report,
+    clear_current_block,
     add_block_mined,
-    add_burned_exe,
     all_blocks,
     all_stats,
     block_count,
     current_block,
+    deposited,
     difficulty,
+    empty_block_config,
+    get_accepted_at,
     get_average_block_time,
     get_balance,
     get_burned_exe,
     get_miner_to_owner_and_index,
+    get_nonce,
     get_stat,
     get_transaction_count,
     get_users_to_block_mined,
+    insert_accepted_at,
     insert_block,
-    insert_new_miner,
-    insert_new_transaction,
     insert_stats,
     latest_block,
+    migrate_balances,
+    mempool_config,
     miner_count,
+    minted,
+    recent_block_times,
+    retarget_config,
+    set_minted,
+    set_withdrawn,
     sub_balance,
+    target,
+    tx_height_count,
     update_average_block_time,
     update_current_block,
     update_difficulty,
+    update_empty_block_config,
+    update_mempool_config,
+    update_nonce,
+    update_retarget_config,
+    update_target,
     update_transaction_count,
+    withdrawn,
+    Account,
+    BatchRef,
     Block,
+    Hash,
     Stats,
     Transaction,
     TransactionArgs,
+    TransactionKind,
+    MAX_MEMO_LEN,
+    SUBACCOUNT_LEN,
 };
-use windoge_pow_backend::miner::{ create_canister, install_code, upgrade_code };
+use windoge_pow_backend::deposits::{
+    self,
+    deposit_account,
+    ClaimDepositArgs,
+    Deposit,
+    PendingDeposits,
+};
+use windoge_pow_backend::history::{
+    account_transactions,
+    index_block,
+    transaction_status,
+    transactions_with_memo,
+    BlockTransaction,
+    TransactionStatus,
+};
+use windoge_pow_backend::ledger::{
+    get_transaction,
+    icrc1_transfer,
+    icrc2_transfer_from,
+    Transaction1,
+    TransferArg,
+    TransferFromArgs,
+};
+use windoge_pow_backend::mempool::{ self, pending_nonce, select_transactions, DropReason };
+use windoge_pow_backend::miner::upgrade_code;
+use windoge_pow_backend::retarget::{
+    difficulty_to_target,
+    header_difficulty,
+    next_target,
+    target_to_difficulty,
+};
+use windoge_pow_backend::supply::{ halving_schedule, max_supply, Halving, Supply };
+use windoge_pow_backend::validation::{
+    check_coinbase,
+    check_batches,
+    check_nonces,
+    check_template,
+    SolutionError,
+};
+use windoge_pow_backend::payments::{ self, PaymentReservation };
+use windoge_pow_backend::provisioning::{
+    self,
+    Provisioning,
+    Provisionings,
+    ProvisioningStatus,
+};
+use windoge_pow_backend::queue::{ self, Requeue, RETRY_INTERVAL_SECS };
+use windoge_pow_backend::withdrawals::{ self, Withdrawal, Withdrawals };
 use windoge_pow_backend::{
     miner_wasm,
     mutate_state,
     read_state,
     replace_state,
+    rewards_at,
+    InitArgs,
     State,
-    BIL_LEDGER_ID,
     BLOCK_HALVING,
     SEC_NANOS,
+    WINDOGE_LEDGER_ID,
+    WINDOGE_MINER_CREATION_AMOUNT,
 };
-use candid::{ CandidType, Decode, Encode, Principal };
+use windoge_protocol::batch_id;
+use candid::{ CandidType, Encode, Principal };
 use ic_cdk::{ init, post_upgrade, query, update };
 
-const WINDOGE_LEDGER_ID: &str = "rh2pm-ryaaa-aaaan-qeniq-cai";
 const WINDOGE_RECEIVER: &str = "zp2fk-qfdts-3jpq4-oe2lv-xphrr-akxnj-dgtwc-f2psp-wsomh-e5gyz-aae";
-const WINDOGE_MINER_CREATION_AMOUNT: u64 = 1500000000; // 15 Windoge98
-const BLOCK_TIME: u64 = 300 * SEC_NANOS; // 5 minutes
-const MAX_DIFFICULTY: u32 = 48;
-const MIN_DIFFICULTY: u32 = 24;
-const TRANSACTION_LIMIT: usize = 150;
+const MEMPOOL_EXPIRY_INTERVAL_SECS: u64 = 60;
 
 fn main() {}
 
 #[init]
-fn init() {
-    let state = State::new();
+fn init(args: Option<InitArgs>) {
+    let mut state = State::new();
+    let args = args.unwrap_or_default();
+
+    let retarget = args.retarget.unwrap_or_default();
+    check_config("retarget", retarget.validate());
+    let _ = update_retarget_config(retarget.clone());
+    state.retarget = retarget;
+
+    let empty_blocks = args.empty_blocks.unwrap_or_default();
+    let _ = update_empty_block_config(empty_blocks.clone());
+    state.empty_blocks = empty_blocks;
+
+    let mempool = args.mempool.unwrap_or_default();
+    check_config("mempool", mempool.validate());
+    let _ = update_mempool_config(mempool.clone());
+    state.mempool_config = mempool;
+
     replace_state(state);
 
+    let _ = set_minted(0);
+    let _ = set_withdrawn(0);
+
     let block = Block::genesis();
     let _ = insert_block(block);
 
     start_next_block(1);
+    start_mempool_expiry();
+    start_queue_retries();
+}
+
+/// Rejects the install or upgrade when its `name` configuration is invalid.
+fn check_config(name: &str, result: Result<(), String>) {
+    if let Err(e) = result {
+        ic_cdk::trap(&format!("invalid {} config: {}", name, e));
+    }
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<InitArgs>) {
     let mut state = State::new();
 
+    // restore configuration, unless new values are passed with the upgrade
+    let args = args.unwrap_or_default();
+    if let Some(retarget) = args.retarget {
+        check_config("retarget", retarget.validate());
+        let _ = update_retarget_config(retarget);
+    }
+    state.retarget = retarget_config();
+
+    if let Some(empty_blocks) = args.empty_blocks {
+        let _ = update_empty_block_config(empty_blocks);
+    }
+    state.empty_blocks = empty_block_config();
+
+    if let Some(mempool) = args.mempool {
+        check_config("mempool", mempool.validate());
+        let _ = update_mempool_config(mempool);
+    }
+    state.mempool_config = mempool_config();
+
     // restore miners
     for (miner, (owner, index)) in get_miner_to_owner_and_index() {
         state.new_miner(miner, owner, index);
     }
 
+    // restore the processed ledger payments, top-ups and refunds included
+    payments::restore(&mut state);
+
+    // move principal balances to their default subaccount
+    let migrated = migrate_balances();
+    if migrated > 0 {
+        ic_cdk::println!("Migrated {} balances to accounts", migrated);
+    }
+
+    // seed the supply totals on the first upgrade that tracks them, by
+    // walking the stored chain on timer ticks
+    let seed_supply = minted().is_none() || withdrawn().is_none();
+    if seed_supply {
+        let _ = set_minted(0);
+        let _ = set_withdrawn(0);
+    }
+
+    // index the chain on the first upgrade that maintains a history index
+    let index_history = tx_height_count() == 0 || account_tx_count() == 0;
+    backfill::start(seed_supply, index_history);
+
+    // restore the mempool and the balances it reserves
+    mempool::restore(&mut state);
+
     // restore burned exe
     let exe = get_burned_exe();
     state.exe_burned = exe;
@@ -90,7 +235,17 @@ fn post_upgrade() {
 
     // restore difficulty
     let current_diff = difficulty();
-    state.current_difficulty = current_diff as u32;
+    if current_diff > 0 {
+        state.current_difficulty = current_diff as u32;
+    }
+
+    // restore target, falling back to the integer difficulty it replaced
+    let current_target = target();
+    state.current_target = if current_target > 0 {
+        current_target
+    } else {
+        difficulty_to_target(state.current_difficulty as f64)
+    };
 
     // restore block height
     if let Some(block) = latest_block() {
@@ -117,6 +272,11 @@ fn post_upgrade() {
     replace_state(state);
 
     start_next_block(1);
+    start_mempool_expiry();
+    start_queue_retries();
+    if backfill::is_running() {
+        run_backfill_step();
+    }
 }
 
 #[query]
@@ -129,6 +289,29 @@ fn get_all_blocks() -> Vec<Block> {
     all_blocks()
 }
 
+#[query]
+fn get_block_accepted_at(height: u64) -> Option<u64> {
+    get_accepted_at(height)
+}
+
+/// Report of the running audit, or of the last finished one.
+#[query]
+fn get_audit_report() -> Option<AuditReport> {
+    audit::progress().or_else(audit_report)
+}
+
+#[update]
+fn start_audit() -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("not allowed".to_string());
+    }
+
+    audit::start(ic_cdk::api::time())?;
+    run_audit_step();
+
+    Ok(())
+}
+
 #[query]
 fn get_all_stats() -> Vec<Stats> {
     all_stats()
@@ -144,6 +327,11 @@ fn get_difficulty() -> u32 {
     read_state(|s| s.current_difficulty)
 }
 
+#[query]
+fn get_next_difficulty() -> f64 {
+    read_state(|s| target_to_difficulty(s.current_target))
+}
+
 #[query]
 fn get_current_rewards() -> u64 {
     read_state(|s| s.current_rewards())
@@ -154,6 +342,26 @@ fn get_current_block() -> Option<Block> {
     read_state(|s| s.current_block.clone())
 }
 
+#[query]
+fn get_supply() -> Supply {
+    let minted = minted().unwrap_or(0);
+    let withdrawn = withdrawn().unwrap_or(0);
+    let deposited = deposited();
+
+    Supply {
+        minted,
+        withdrawn,
+        deposited,
+        circulating: (minted + deposited).saturating_sub(withdrawn),
+        max_supply: max_supply(),
+    }
+}
+
+#[query]
+fn get_halving_schedule() -> Vec<Halving> {
+    halving_schedule(ic_cdk::api::time(), read_state(|s| s.average_block_time))
+}
+
 #[query]
 fn get_next_halving() -> u64 {
     let mined_blocks = block_count();
@@ -166,14 +374,72 @@ fn get_mempool() -> Vec<Transaction> {
     read_state(|s| s.mempool.clone())
 }
 
+#[query]
+fn get_withdrawal(tx_id: Hash) -> Option<Withdrawal> {
+    memory::get_withdrawal(tx_id)
+}
+
+/// Queues a withdrawal the ledger rejected again.
+#[update]
+fn retry_withdrawal(tx_id: Hash) -> Result<(), String> {
+    retry_queued::<Withdrawals>(tx_id)
+}
+
+#[query]
+fn get_provisioning_status(block_index: u64) -> Option<Provisioning> {
+    memory::get_provisioning(block_index)
+}
+
+/// Queues a miner refund the ledger rejected again.
+#[update]
+fn retry_provisioning(block_index: u64) -> Result<(), String> {
+    retry_queued::<Provisionings>(block_index)
+}
+
+/// Lets a controller queue the rejected entry `key` of `Q` again, and
+/// processes it right away.
+fn retry_queued<Q: Requeue>(key: Q::Key) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("not allowed".to_string());
+    }
+
+    Q::retry(key.clone(), ic_cdk::api::time())?;
+    ic_cdk::spawn(Q::process(key));
+    Ok(())
+}
+
+#[query]
+fn get_transaction_status(tx_id: Hash) -> Option<TransactionStatus> {
+    read_state(|s| transaction_status(s, tx_id))
+}
+
+/// Mined transactions of `account`, newest first, skipping the `start` newest
+/// ones.
+#[query]
+fn get_account_transactions(account: Account, start: u64, limit: u64) -> Vec<BlockTransaction> {
+    account_transactions(&Account::new(account.owner, account.subaccount), start, limit)
+}
+
+/// Latest mined transactions carrying `memo`, newest first.
+#[query]
+fn get_transactions_by_memo(memo: serde_bytes::ByteBuf) -> Vec<BlockTransaction> {
+    transactions_with_memo(&memo)
+}
+
 #[query]
 pub fn get_state() -> State {
     read_state(|s| s.clone())
 }
 
 #[query]
-fn get_balance_of(user: Principal) -> u64 {
-    get_balance(user)
+fn get_balance_of(account: Account) -> u64 {
+    get_balance(&Account::new(account.owner, account.subaccount))
+}
+
+#[query]
+fn get_next_nonce(user: Principal) -> u64 {
+    let pending = read_state(|s| pending_nonce(&s.mempool, user)).unwrap_or(0);
+    pending.max(get_nonce(user)).saturating_add(1)
 }
 
 #[query]
@@ -193,9 +459,7 @@ async fn topup_miner(miner: Principal, block_index: u64) -> Result<String, Strin
     }
 
     if let Some(_) = read_state(|s| s.miner_to_owner.get(&miner).cloned()) {
-        if read_state(|s| s.miner_creation_transactions.contains(&block_index)) {
-            return Err("transaction already processed".to_string());
-        }
+        let reservation = payments::reserve(block_index)?;
 
         let index = candid::Nat::from(block_index);
         let transaction = fetch_block(index).await?;
@@ -208,41 +472,7 @@ async fn topup_miner(miner: Principal, block_index: u64) -> Result<String, Strin
                 return Err("transfer not to BIL canister".to_string());
             }
 
-            let burn_amount = nat_to_u64((transfer.amount.clone() * 10_u128) / 100_u128)?;
-            match
-                burn_exe(BurnArgs {
-                    memo: None,
-                    from_subaccount: None,
-                    created_at_time: None,
-                    amount: candid::Nat::from(burn_amount),
-                }).await
-            {
-                Ok(index) => {
-                    ic_cdk::println!("Burned {} EXE, index: {:?}", burn_amount, index);
-                    mutate_state(|s| {
-                        s.exe_burned += burn_amount;
-                    });
-                    let _ = add_burned_exe(burn_amount);
-                }
-                Err(e) => ic_cdk::println!("Error burning {} EXE: {:?}", burn_amount, e),
-            }
-
-            let cycles_amount = tokens_to_cycles(nat_to_u64(transfer.amount)?);
-
-            match transfer_cycles(miner, (cycles_amount * 80) / 100).await {
-                Ok(_) => {
-                    let _ = insert_new_transaction(block_index);
-                    mutate_state(|s| {
-                        s.miner_creation_transactions.insert(block_index);
-                    });
-                    ic_cdk::println!("Topped up miner {}", miner.to_text());
-                    return Ok("topped up miner".to_string());
-                }
-                Err(e) => {
-                    ic_cdk::println!("Error topping up miner: {:?}", e);
-                    return Err("error topping up miner".to_string());
-                }
-            }
+            fund_miner(miner, nat_to_u64(transfer.amount)?, reservation).await
         } else {
             return Err("expected transfer".to_string());
         }
@@ -251,50 +481,437 @@ async fn topup_miner(miner: Principal, block_index: u64) -> Result<String, Strin
     }
 }
 
+/// Tops up `miner` with the caller's ICRC-2 approval of `amount` on the EXE
+/// ledger, in one call. The payment is refunded if the top-up fails.
 #[update]
-async fn create_transaction(transaction_arg: TransactionArgs) -> Result<String, String> {
-    if ic_cdk::caller() == Principal::anonymous() {
+async fn topup_miner_with_approval(
+    miner: Principal,
+    amount: u64,
+    from_subaccount: Option<serde_bytes::ByteBuf>
+) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
         return Err("caller is anonymous".to_string());
     }
+    if !read_state(|s| s.miner_to_owner.contains_key(&miner)) {
+        return Err("miner not found".to_string());
+    }
+    check_subaccount(&from_subaccount)?;
 
-    let pending_amount = read_state(|s|
-        s.pending_balance.get(&ic_cdk::caller()).cloned().unwrap_or(0)
-    );
-    let actual_balance = get_balance(ic_cdk::caller());
+    let from = Account {
+        owner: caller,
+        subaccount: from_subaccount,
+    };
+    let block_index = collect_payment(&from, amount).await?;
+    let reservation = payments::reserve(block_index)?;
 
-    if actual_balance < transaction_arg.amount + pending_amount {
-        return Err("insufficient balance".to_string());
+    match fund_miner(miner, amount, reservation).await {
+        Ok(result) => Ok(result),
+        Err(e) => Err(refund_payment(from, amount, block_index, e).await),
+    }
+}
+
+/// Sends `miner` the cycles bought with the reserved EXE payment of `amount`,
+/// then burns a share of it.
+async fn fund_miner(
+    miner: Principal,
+    amount: u64,
+    reservation: PaymentReservation
+) -> Result<String, String> {
+    let cycles_amount = tokens_to_cycles(amount);
+
+    if let Err(e) = transfer_cycles(miner, (cycles_amount * 80) / 100).await {
+        ic_cdk::println!("Error topping up miner: {:?}", e);
+        return Err("error topping up miner".to_string());
+    }
+    reservation.commit();
+
+    provisioning::burn_exe((amount * 10) / 100).await;
+
+    ic_cdk::println!("Topped up miner {}", miner.to_text());
+    Ok("topped up miner".to_string())
+}
+
+/// Pulls `amount` EXE approved by `from` to the backend. Returns the index of
+/// the transfer on the EXE ledger.
+async fn collect_payment(from: &Account, amount: u64) -> Result<u64, String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: from.clone(),
+        to: Account::from(ic_cdk::id()),
+        amount: amount.into(),
+        fee: None,
+        memo: None,
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+
+    let ledger = Principal::from_text(WINDOGE_LEDGER_ID).unwrap();
+    let index = icrc2_transfer_from(args, ledger).await?.map_err(|e| format!("{:?}", e))?;
+    nat_to_u64(index)
+}
+
+/// Returns the payment `block_index` to `to` after `error` and commits it.
+/// If the refund fails too, the payment is released so it can still be
+/// redeemed with `topup_miner`. Returns the error for the caller.
+async fn refund_payment(to: Account, amount: u64, block_index: u64, error: String) -> String {
+    let reservation = match payments::reserve(block_index) {
+        Ok(reservation) => reservation,
+        Err(e) => {
+            return format!("{}, {}", error, e);
+        }
+    };
+
+    let transfer = TransferArg {
+        to,
+        fee: None,
+        memo: Some(serde_bytes::ByteBuf::from(block_index.to_le_bytes().to_vec())),
+        from_subaccount: None,
+        created_at_time: Some(ic_cdk::api::time()),
+        amount: amount.into(),
+    };
+
+    let ledger = Principal::from_text(WINDOGE_LEDGER_ID).unwrap();
+    match icrc1_transfer(transfer, ledger).await {
+        Ok(Ok(_)) => {
+            reservation.commit();
+            format!("{}, payment refunded", error)
+        }
+        Ok(Err(e)) => refund_failed(error, block_index, format!("{:?}", e)),
+        Err(e) => refund_failed(error, block_index, e),
+    }
+}
+
+fn refund_failed(error: String, block_index: u64, refund_error: String) -> String {
+    ic_cdk::println!("Error refunding payment {}: {}", block_index, refund_error);
+    format!("{}, refund failed, payment {} can be redeemed by its block index", error, block_index)
+}
+
+fn check_memo(transaction_arg: &TransactionArgs) -> Result<(), String> {
+    match &transaction_arg.memo {
+        Some(memo) if memo.len() > MAX_MEMO_LEN => {
+            Err(format!("memo can't be longer than {} bytes", MAX_MEMO_LEN))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_subaccount(subaccount: &Option<serde_bytes::ByteBuf>) -> Result<(), String> {
+    if subaccount.as_ref().is_some_and(|s| s.len() != SUBACCOUNT_LEN) {
+        return Err(format!("subaccounts must be {} bytes long", SUBACCOUNT_LEN));
     }
+    Ok(())
+}
 
-    if read_state(|s| s.mempool.len()) > TRANSACTION_LIMIT {
-        return Err("network is congested, transactions can be processed in next block".to_string());
+fn check_subaccounts(transaction_arg: &TransactionArgs) -> Result<(), String> {
+    check_subaccount(&transaction_arg.from_subaccount)?;
+    check_subaccount(&transaction_arg.to_subaccount)
+}
+
+/// Queues a transfer from the caller. Returns the id of the transaction.
+#[update]
+async fn create_transaction(transaction_arg: TransactionArgs) -> Result<Hash, String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
+    }
+
+    check_subaccounts(&transaction_arg)?;
+    let from = Account::new(ic_cdk::caller(), transaction_arg.from_subaccount.clone());
+    let pending_amount = read_state(|s| s.pending_balance.get(&from).cloned().unwrap_or(0));
+    let actual_balance = get_balance(&from);
+    let fee = transaction_arg.fee.unwrap_or(0);
+    let total = transaction_arg.amount.checked_add(fee).ok_or("amount overflow".to_string())?;
+
+    let needed = total.checked_add(pending_amount).ok_or("amount overflow".to_string())?;
+    if actual_balance < needed {
+        return Err("insufficient balance".to_string());
     }
 
     if transaction_arg.amount < 1 && transaction_arg.recipient == ic_cdk::id() {
         return Err("amount must be greater than 0".to_string());
     }
+    check_memo(&transaction_arg)?;
+
+    let latest_nonce = read_state(|s| pending_nonce(&s.mempool, ic_cdk::caller()))
+        .unwrap_or(0)
+        .max(get_nonce(ic_cdk::caller()));
+    let nonce = match transaction_arg.nonce {
+        Some(nonce) if nonce <= latest_nonce => {
+            return Err(format!("nonce must be greater than {}", latest_nonce));
+        }
+        Some(nonce) => nonce,
+        None => latest_nonce.checked_add(1).ok_or("nonce exhausted".to_string())?,
+    };
+
+    let transaction = Transaction {
+        sender: ic_cdk::caller(),
+        recipient: transaction_arg.recipient,
+        amount: transaction_arg.amount,
+        timestamp: ic_cdk::api::time(),
+        kind: TransactionKind::Transfer,
+        fee,
+        nonce,
+        batch: None,
+        memo: transaction_arg.memo,
+        from_subaccount: from.subaccount.clone(),
+        to_subaccount: Account::new(transaction_arg.recipient, transaction_arg.to_subaccount)
+            .subaccount,
+    };
+
+    let tx_id = transaction.id();
+    mutate_state(|s| mempool::admit(s, vec![transaction]))?;
 
     mutate_state(|s| {
         s.pending_balance
-            .entry(ic_cdk::caller())
+            .entry(from)
+            .and_modify(|e| {
+                *e += total;
+            })
+            .or_insert(total);
+    });
+
+    Ok(tx_id)
+}
+
+/// Creates transfers from the caller that are reserved together and included
+/// in one block together. Nonces are assigned in order. Returns the id of the
+/// batch.
+#[update]
+fn create_batch_transaction(transaction_args: Vec<TransactionArgs>) -> Result<Hash, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
+    }
+
+    if transaction_args.is_empty() {
+        return Err("batch is empty".to_string());
+    }
+    // a batch has to fit in one block and in the sender's mempool allowance
+    let max_size = read_state(|s| {
+        let config = &s.mempool_config;
+        config.max_block_transactions.min(config.max_per_sender).min(config.max_size)
+    });
+    if (transaction_args.len() as u64) > max_size {
+        return Err(format!("batch can't have more than {} transactions", max_size));
+    }
+
+    // every transaction of a batch debits the same account
+    let from_subaccount = transaction_args[0].from_subaccount.clone();
+    let mut total: u64 = 0;
+    for transaction_arg in &transaction_args {
+        if transaction_arg.from_subaccount != from_subaccount {
+            return Err("batched transactions must debit the same subaccount".to_string());
+        }
+        if transaction_arg.nonce.is_some() {
+            return Err("nonces of batched transactions are assigned by the batch".to_string());
+        }
+        if transaction_arg.amount < 1 && transaction_arg.recipient == ic_cdk::id() {
+            return Err("amount must be greater than 0".to_string());
+        }
+        check_memo(transaction_arg)?;
+        check_subaccounts(transaction_arg)?;
+        total = transaction_arg.amount
+            .checked_add(transaction_arg.fee.unwrap_or(0))
+            .and_then(|amount| total.checked_add(amount))
+            .ok_or("amount overflow".to_string())?;
+    }
+
+    let from = Account::new(caller, from_subaccount);
+    let pending_amount = read_state(|s| s.pending_balance.get(&from).cloned().unwrap_or(0));
+    let needed = total.checked_add(pending_amount).ok_or("amount overflow".to_string())?;
+    if get_balance(&from) < needed {
+        return Err("insufficient balance".to_string());
+    }
+
+    let latest_nonce = read_state(|s| pending_nonce(&s.mempool, caller))
+        .unwrap_or(0)
+        .max(get_nonce(caller));
+    let timestamp = ic_cdk::api::time();
+
+    let mut transactions = Vec::with_capacity(transaction_args.len());
+    for (offset, transaction_arg) in transaction_args.into_iter().enumerate() {
+        let nonce = latest_nonce
+            .checked_add((offset as u64) + 1)
+            .ok_or("nonce exhausted".to_string())?;
+        transactions.push(Transaction {
+            sender: caller,
+            recipient: transaction_arg.recipient,
+            amount: transaction_arg.amount,
+            timestamp,
+            kind: TransactionKind::Transfer,
+            fee: transaction_arg.fee.unwrap_or(0),
+            nonce,
+            batch: None,
+            memo: transaction_arg.memo,
+            from_subaccount: from.subaccount.clone(),
+            to_subaccount: Account::new(transaction_arg.recipient, transaction_arg.to_subaccount)
+                .subaccount,
+        });
+    }
+
+    let batch = BatchRef {
+        id: batch_id(&transactions),
+        size: transactions.len() as u32,
+    };
+    for transaction in transactions.iter_mut() {
+        transaction.batch = Some(batch);
+    }
+
+    mutate_state(|s| mempool::admit(s, transactions))?;
+
+    mutate_state(|s| {
+        s.pending_balance
+            .entry(from)
             .and_modify(|e| {
-                *e += transaction_arg.amount;
+                *e += total;
             })
-            .or_insert(transaction_arg.amount);
+            .or_insert(total);
     });
 
+    Ok(batch.id)
+}
+
+/// Finds a transaction of `sender` that is still waiting in the mempool and
+/// not part of the published template. `tx_id` may also be the id of a batch,
+/// which finds its first transaction.
+fn pending_transaction(sender: Principal, tx_id: Hash) -> Result<(usize, Transaction), String> {
+    read_state(|s| {
+        let (pos, transaction) = s.mempool
+            .iter()
+            .enumerate()
+            .find(|(_, tx)| {
+                tx.sender == sender && mempool::has_id(tx, tx_id)
+            })
+            .ok_or("transaction not found".to_string())?;
+
+        // batches are included whole, so one transaction tells for all of them
+        if mempool::in_template(s, transaction.id()) {
+            return Err("transaction is already in the current block".to_string());
+        }
+
+        Ok((pos, transaction.clone()))
+    })
+}
+
+#[update]
+fn cancel_transaction(tx_id: Hash) -> Result<String, String> {
+    let (pos, transaction) = pending_transaction(ic_cdk::caller(), tx_id)?;
+
+    // cancelling any transaction of a batch cancels the whole batch
+    match transaction.batch {
+        Some(batch) => {
+            mutate_state(|s| mempool::discard_batch(s, batch.id, DropReason::Cancelled));
+        }
+        None => {
+            mutate_state(|s| mempool::discard(s, pos, DropReason::Cancelled));
+        }
+    }
+
+    Ok("transaction cancelled".to_string())
+}
+
+/// Replaces a pending transaction in place. The replacement keeps the nonce
+/// of the transaction it replaces. Returns the id of the replacement.
+#[update]
+fn replace_transaction(tx_id: Hash, transaction_arg: TransactionArgs) -> Result<Hash, String> {
+    let (pos, previous) = pending_transaction(ic_cdk::caller(), tx_id)?;
+
+    if previous.batch.is_some() {
+        return Err("batched transactions can't be replaced".to_string());
+    }
+
+    if transaction_arg.nonce.is_some_and(|nonce| nonce != previous.nonce) {
+        return Err("replacement must keep the nonce of the replaced transaction".to_string());
+    }
+
+    check_subaccounts(&transaction_arg)?;
+    let from = previous.from();
+    if Account::new(previous.sender, transaction_arg.from_subaccount.clone()) != from {
+        return Err("replacement must debit the subaccount of the replaced transaction".to_string());
+    }
+
+    let fee = transaction_arg.fee.unwrap_or(0);
+    let total = transaction_arg.amount.checked_add(fee).ok_or("amount overflow".to_string())?;
+    let pending_amount = read_state(|s|
+        s.pending_balance.get(&from).cloned().unwrap_or(0)
+    ).saturating_sub(previous.amount + previous.fee);
+
+    let needed = total.checked_add(pending_amount).ok_or("amount overflow".to_string())?;
+    if get_balance(&from) < needed {
+        return Err("insufficient balance".to_string());
+    }
+
+    if transaction_arg.amount < 1 && transaction_arg.recipient == ic_cdk::id() {
+        return Err("amount must be greater than 0".to_string());
+    }
+    check_memo(&transaction_arg)?;
+
     let transaction = Transaction {
-        sender: ic_cdk::caller(),
+        sender: previous.sender,
         recipient: transaction_arg.recipient,
         amount: transaction_arg.amount,
         timestamp: ic_cdk::api::time(),
+        kind: TransactionKind::Transfer,
+        fee,
+        nonce: previous.nonce,
+        batch: None,
+        memo: transaction_arg.memo,
+        from_subaccount: from.subaccount.clone(),
+        to_subaccount: Account::new(transaction_arg.recipient, transaction_arg.to_subaccount)
+            .subaccount,
     };
 
+    let replacement_id = transaction.id();
     mutate_state(|s| {
-        s.mempool.push(transaction.clone());
+        mempool::replace(s, pos, transaction);
+        s.pending_balance.insert(from, pending_amount + total);
     });
 
-    Ok("transaction created".to_string())
+    Ok(replacement_id)
+}
+
+/// Account on the BIL ledger that takes deposits for `account`.
+#[query]
+fn get_deposit_account(account: Account) -> Account {
+    deposit_account(&Account::new(account.owner, account.subaccount))
+}
+
+/// Credits a transfer to the deposit account of the caller's `subaccount`,
+/// given its index on the BIL ledger.
+#[update]
+async fn notify_deposit(
+    block_index: u64,
+    subaccount: Option<serde_bytes::ByteBuf>
+) -> Result<Deposit, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
+    }
+    check_subaccount(&subaccount)?;
+
+    deposits::notify(Account::new(caller, subaccount), block_index, ic_cdk::api::time()).await
+}
+
+/// Deposits BIL the caller approved the backend to spend.
+#[update]
+async fn claim_deposit(args: ClaimDepositArgs) -> Result<Deposit, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
+    }
+    check_subaccount(&args.from_subaccount)?;
+    check_subaccount(&args.to_subaccount)?;
+
+    let from = Account {
+        owner: caller,
+        subaccount: args.from_subaccount,
+    };
+    let account = Account::new(caller, args.to_subaccount);
+    deposits::claim(from, account, args.amount, ic_cdk::api::time()).await
+}
+
+#[query]
+fn get_deposit(block_index: u64) -> Option<Deposit> {
+    memory::get_deposit(block_index)
 }
 
 #[update]
@@ -303,9 +920,7 @@ async fn spawn_miner(block_index: u64) -> Result<Principal, String> {
         return Err("caller is anonymous".to_string());
     }
 
-    if read_state(|s| s.miner_creation_transactions.contains(&block_index)) {
-        return Err("transaction already processed".to_string());
-    }
+    let reservation = payments::reserve(block_index)?;
 
     let index = candid::Nat::from(block_index);
     let transaction = fetch_block(index).await?;
@@ -320,61 +935,71 @@ async fn spawn_miner(block_index: u64) -> Result<Principal, String> {
         if transfer.to.owner != ic_cdk::id() {
             return Err("transfer not to BIL canister".to_string());
         }
+
+        let amount = nat_to_u64(transfer.amount)?;
+        provisioning::start(reservation, ic_cdk::caller(), transfer.from, amount);
+        provision(block_index).await
     } else {
         return Err("expected transfer".to_string());
     }
+}
 
-    let arg = Encode!(&ic_cdk::caller()).unwrap();
-
-    let canister_id = create_canister(2_500_000_000_000).await.map_err(|e|
-        format!("{} - {:?}", e.method, e.reason)
-    )?;
+/// Spawns a miner paid for by the caller's ICRC-2 approval on the EXE ledger,
+/// in one call. The payment is refunded if the miner can't be created.
+#[update]
+async fn spawn_miner_with_approval(
+    from_subaccount: Option<serde_bytes::ByteBuf>
+) -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
+    }
+    check_subaccount(&from_subaccount)?;
 
-    install_code(canister_id, miner_wasm().to_vec(), arg).await.map_err(|e|
-        format!("{} - {:?}", e.method, e.reason)
-    )?;
+    let from = Account {
+        owner: caller,
+        subaccount: from_subaccount,
+    };
+    let block_index = collect_payment(&from, WINDOGE_MINER_CREATION_AMOUNT).await?;
+    let reservation = payments::reserve(block_index)?;
 
-    mutate_state(|s| {
-        s.new_miner(canister_id, ic_cdk::caller(), block_index);
-    });
-    insert_new_miner(canister_id, ic_cdk::caller(), block_index);
-    let _ = insert_new_transaction(block_index);
+    provisioning::start(reservation, caller, from, WINDOGE_MINER_CREATION_AMOUNT);
+    provision(block_index).await
+}
 
-    let burned_amount = (WINDOGE_MINER_CREATION_AMOUNT * 40) / 100;
-    match
-        burn_exe(BurnArgs {
-            memo: None,
-            from_subaccount: None,
-            created_at_time: None,
-            amount: burned_amount.into(),
-        }).await
-    {
-        Ok(index) => {
-            ic_cdk::println!("Burned {} EXE, index: {:?}", burned_amount, index);
-            mutate_state(|s| {
-                s.exe_burned += burned_amount;
-            });
-            let _ = add_burned_exe(burned_amount);
+/// Runs the provisioning of `block_index` as far as it gets in this call.
+/// Steps left to retry are run by a timer, see `get_provisioning_status`.
+async fn provision(block_index: u64) -> Result<Principal, String> {
+    provisioning::process(block_index).await;
+
+    let provisioning = memory
+        ::get_provisioning(block_index)
+        .ok_or("provisioning not found".to_string())?;
+    match (provisioning.status, provisioning.canister_id) {
+        (ProvisioningStatus::Completed, Some(canister_id)) => Ok(canister_id),
+        (ProvisioningStatus::Refunded { error, .. }, _) => {
+            Err(format!("{}, payment refunded", error))
         }
-        Err(e) => ic_cdk::println!("Error burning EXE: {:?}", e),
+        (ProvisioningStatus::RefundFailed { error }, _) => Err(error),
+        _ => Err("miner provisioning in progress".to_string()),
     }
-
-    ic_cdk::println!("Miner {} spawned", canister_id.to_text());
-
-    Ok(canister_id)
 }
 
 #[update(hidden = true)]
 async fn submit_solution(block: Block, stats: Stats) -> Result<bool, String> {
     if let Err(e) = validate_solution(&block) {
         ic_cdk::println!("Solution from miner {} rejected: {}", ic_cdk::caller().to_text(), e);
-        return Err(e);
+        return Err(e.to_string());
     }
 
     let miner_owner = read_state(|s|
         s.miner_to_owner.get(&ic_cdk::caller()).cloned().unwrap_or(Principal::anonymous())
     );
 
+    // the miner's stats are telemetry only, block time is measured on chain
+    let accepted_at = ic_cdk::api::time();
+    let solve_time = accepted_at.saturating_sub(block.header.timestamp);
+
     mutate_state(|s| {
         s.miner_to_mined_block
             .entry(miner_owner)
@@ -392,84 +1017,64 @@ async fn submit_solution(block: Block, stats: Stats) -> Result<bool, String> {
             .or_insert(stats.cycles_burned);
 
         let block_time =
-            (s.average_block_time * (block.header.height - 1) + stats.solve_time) /
+            (s.average_block_time * (block.header.height - 1) + solve_time) /
             block.header.height;
         s.average_block_time = block_time;
         let _ = update_average_block_time(block_time);
 
         s.block_height = block.header.height;
-        s.transaction_count += block.transactions.len() as u64;
-        let _ = update_transaction_count(block.transactions.len() as u64);
-
-        for tx in &block.transactions {
-            if let Some(pos) = s.mempool.iter().position(|x| x == tx) {
-                s.mempool.remove(pos);
-            }
-
-            if let Some(_) = s.pending_balance.get(&tx.sender) {
-                s.pending_balance.entry(tx.sender).and_modify(|e| {
-                    *e -= tx.amount;
-                });
-            }
+        let transfers = block.transactions
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .count() as u64;
+        s.transaction_count += transfers;
+        let _ = update_transaction_count(transfers);
+
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            mempool::remove_by_id(s, tx.id());
+            update_nonce(tx.sender, tx.nonce);
         }
     });
 
     let _ = insert_block(block.clone());
+    insert_accepted_at(block.header.height, accepted_at);
+    index_block(&block);
     let _ = insert_stats(stats.clone());
 
+    let fees = block.fees();
+
+    // templates published before the coinbase was introduced carry none
+    if block.coinbase().is_none() {
+        let reward = read_state(|s| s.current_rewards());
+        add_balance(&Account::from(miner_owner), reward);
+        let _ = add_minted(reward);
+    }
+
     for transaction in block.transactions {
-        if transaction.recipient == ic_cdk::id() {
-            let transfer = TransferArg {
-                to: Account {
-                    owner: transaction.sender,
-                    subaccount: None,
-                },
-                fee: None,
-                from_subaccount: None,
-                memo: None,
-                created_at_time: None,
-                amount: transaction.amount.into(),
-            };
-            ic_cdk::spawn(async move {
-                match icrc1_transfer(transfer, Principal::from_text(BIL_LEDGER_ID).unwrap()).await {
-                    Ok(_) => {
-                        ic_cdk::println!("BIL minted successfully");
-                        sub_balance(transaction.sender, transaction.amount);
-                    }
-                    Err(e) => {
-                        ic_cdk::println!("Error minting BIL: {:?}", e);
-                    }
-                }
-            });
-        } else {
-            add_balance(transaction.recipient, transaction.amount);
-            sub_balance(transaction.sender, transaction.amount);
+        if transaction.is_coinbase() {
+            add_balance(&transaction.to(), transaction.amount);
+            let _ = add_minted(transaction.amount.saturating_sub(fees));
+            continue;
         }
-    }
 
-    add_balance(
-        miner_owner,
-        read_state(|s| s.current_rewards())
-    );
+        // the fee goes to the coinbase, whatever happens to the amount
+        let from = transaction.from();
+        sub_balance(&from, transaction.fee);
 
-    if BLOCK_TIME > stats.solve_time {
-        let sec = (BLOCK_TIME - stats.solve_time) / SEC_NANOS;
-        if sec > 60 && read_state(|s| s.current_difficulty) < MAX_DIFFICULTY {
-            mutate_state(|s| {
-                s.current_difficulty = s.current_difficulty + 1;
-                let _ = update_difficulty(s.current_difficulty as u64);
-            });
-        }
-    } else {
-        let sec = (stats.solve_time - BLOCK_TIME) / SEC_NANOS;
-        if sec > 60 && read_state(|s| s.current_difficulty) > MIN_DIFFICULTY {
-            mutate_state(|s| {
-                s.current_difficulty = s.current_difficulty - 1;
-                let _ = update_difficulty(s.current_difficulty as u64);
-            });
+        if transaction.recipient == ic_cdk::id() {
+            // BIL is minted to the same account on the ledger
+            match withdrawals::enqueue(&transaction, accepted_at) {
+                Ok(tx_id) => ic_cdk::spawn(withdrawals::process(tx_id)),
+                Err(e) => ic_cdk::println!("Withdrawal not queued: {}", e),
+            }
+        } else {
+            add_balance(&transaction.to(), transaction.amount);
+            sub_balance(&from, transaction.amount);
         }
     }
 
+    retarget();
+
     ic_cdk::println!("Solution from miner {} accepted", ic_cdk::caller().to_text());
 
     start_next_block(1);
@@ -477,54 +1082,131 @@ async fn submit_solution(block: Block, stats: Stats) -> Result<bool, String> {
     Ok(true)
 }
 
-fn validate_solution(block: &Block) -> Result<(), String> {
+fn validate_solution(block: &Block) -> Result<(), SolutionError> {
     if ic_cdk::caller() == Principal::anonymous() {
-        return Err("caller is anonymous".to_string());
+        return Err(SolutionError::AnonymousCaller);
     }
 
     if !read_state(|s| s.miner_to_owner.contains_key(&ic_cdk::caller())) {
-        return Err("Unregistered miner".to_string());
+        return Err(SolutionError::UnregisteredMiner);
     }
 
     if let Some(latest_block) = latest_block() {
         if block.header.prev_hash != latest_block.hash {
-            return Err("Block references outdated chain state".to_string());
+            return Err(SolutionError::OutdatedChainState);
         }
     } else {
         if block.header.prev_hash != 0 {
-            return Err("Block references outdated chain state".to_string());
+            return Err(SolutionError::OutdatedChainState);
         }
     }
 
     if block.header.height != block_count() {
-        return Err("Block height mismatch".to_string());
+        return Err(SolutionError::HeightMismatch);
     }
 
-    let mut hasher = RapidHasher::new(0);
-    let mut data = Vec::new();
-    data.extend_from_slice(&block.header.version.to_le_bytes());
-    data.extend_from_slice(&block.header.prev_hash.to_le_bytes());
-    data.extend_from_slice(&block.header.merkle_root.to_le_bytes());
-    data.extend_from_slice(&block.header.timestamp.to_le_bytes());
-    data.extend_from_slice(&block.nonce.to_le_bytes());
+    let template = match read_state(|s| s.current_block.clone()) {
+        Some(template) => template,
+        None => {
+            return Err(SolutionError::NoTemplate);
+        }
+    };
+    check_template(block, &template)?;
 
-    hasher.write(&data);
-    let hash64 = hasher.finish();
+    if template.coinbase().is_some() {
+        let owner = read_state(|s| s.miner_to_owner.get(&ic_cdk::caller()).cloned()).ok_or(
+            SolutionError::UnregisteredMiner
+        )?;
+        check_coinbase(block, owner, rewards_at(block.header.height) + block.fees())?;
+    }
 
-    let hash128_high = {
-        let mut hasher = RapidHasher::new(hash64);
-        hasher.write(&hash64.to_le_bytes());
-        hasher.finish()
-    };
+    check_nonces(block, get_nonce)?;
+    check_batches(block)?;
 
-    let hash_value = ((hash128_high as u128) << 64) | (hash64 as u128);
-    if hash_value.leading_zeros() < block.header.difficulty {
-        return Err("Invalid solution".to_string());
+    let hash_value = block.calculate_hash();
+    if block.header.version >= 2 && block.hash != hash_value {
+        return Err(SolutionError::InvalidHash);
+    }
+
+    if !block.meets_target(hash_value) {
+        return Err(SolutionError::InvalidSolution);
     }
 
     Ok(())
 }
 
+fn retarget() {
+    let window = read_state(|s| s.retarget.window);
+    let solve_times = recent_block_times(window);
+
+    mutate_state(|s| {
+        s.current_target = next_target(s.current_target, &solve_times, &s.retarget);
+        s.current_difficulty = header_difficulty(s.current_target);
+        let _ = update_target(s.current_target);
+        let _ = update_difficulty(s.current_difficulty as u64);
+    });
+}
+
+/// Seconds until an empty template may be published, or `u64::MAX` when
+/// empty blocks are disabled.
+fn empty_block_wait() -> u64 {
+    let config = read_state(|s| s.empty_blocks.clone());
+    if !config.enabled {
+        return u64::MAX;
+    }
+
+    let last_block_at = latest_block()
+        .map(|block| get_accepted_at(block.header.height).unwrap_or(block.header.timestamp))
+        .unwrap_or(0);
+    let elapsed = ic_cdk::api::time().saturating_sub(last_block_at) / SEC_NANOS;
+
+    config.min_interval_secs.saturating_sub(elapsed)
+}
+
+fn run_audit_step() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        if audit::step(ic_cdk::api::time()) {
+            run_audit_step();
+        } else {
+            ic_cdk::println!("Audit finished");
+        }
+    });
+}
+
+fn run_backfill_step() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        if backfill::step(ic_cdk::id()) {
+            run_backfill_step();
+        } else {
+            ic_cdk::println!("Backfill finished");
+        }
+    });
+}
+
+fn start_mempool_expiry() {
+    let interval = std::time::Duration::from_secs(MEMPOOL_EXPIRY_INTERVAL_SECS);
+    ic_cdk_timers::set_timer_interval(interval, || {
+        let stale_template = mutate_state(|s| mempool::expire(s, ic_cdk::api::time()));
+        if stale_template {
+            // retire the template so its expired transactions can't be mined
+            mutate_state(|s| {
+                s.current_block = None;
+            });
+            clear_current_block();
+            create_block();
+        }
+    });
+}
+
+fn start_queue_retries() {
+    let interval = std::time::Duration::from_secs(RETRY_INTERVAL_SECS);
+    ic_cdk_timers::set_timer_interval(interval, || {
+        queue::process_queue::<Withdrawals>();
+        queue::process_queue::<Provisionings>();
+        queue::process_queue::<PendingDeposits>();
+    });
+}
+
 fn start_next_block(sec: u64) {
     ic_cdk::println!("Starting next block in {} seconds", sec);
     ic_cdk_timers::set_timer(std::time::Duration::from_secs(sec), || {
@@ -533,21 +1215,36 @@ fn start_next_block(sec: u64) {
 }
 
 fn create_block() {
-    let transactions = read_state(|s| s.mempool.clone());
+    let transactions = read_state(|s| select_transactions(&s.mempool, &s.mempool_config));
     if transactions.is_empty() {
-        ic_cdk::println!("No transactions to include in block");
-        start_next_block(20);
-        return;
+        let wait = empty_block_wait();
+        if wait > 0 {
+            ic_cdk::println!("No transactions to include in block");
+            start_next_block(wait.min(20));
+            return;
+        }
+        ic_cdk::println!("Creating empty block");
+    } else {
+        ic_cdk::println!("Creating block with {} transactions", transactions.len());
     }
 
-    ic_cdk::println!("Creating block with {} transactions", transactions.len());
-
     let prev_block = latest_block().unwrap();
-    mutate_state(|s| {
-        s.current_difficulty = 30;
-    });
-    let difficulty = read_state(|s| s.current_difficulty);
-    match Block::new(&prev_block, transactions, difficulty) {
+    let target = read_state(|s| s.current_target);
+    let now = ic_cdk::api::time();
+
+    // the recipient is left anonymous and filled in by the miner that solves it
+    let fees: u64 = transactions
+        .iter()
+        .map(|tx| tx.fee)
+        .sum();
+    let coinbase = Transaction::coinbase(
+        Principal::anonymous(),
+        rewards_at(prev_block.header.height + 1) + fees,
+        now
+    );
+    let transactions = std::iter::once(coinbase).chain(transactions).collect();
+
+    match Block::new(&prev_block, transactions, target, now) {
         Ok(block) => {
             ic_cdk::println!("Block created successfully!");
             mutate_state(|s| {
@@ -561,127 +1258,8 @@ fn create_block() {
     }
 }
 
-#[derive(CandidType, candid::Deserialize)]
-pub struct Account {
-    pub owner: Principal,
-    pub subaccount: Option<serde_bytes::ByteBuf>,
-}
-
-#[derive(CandidType, candid::Deserialize)]
-pub struct Burn {
-    pub from: Account,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
-}
-
-#[derive(CandidType, candid::Deserialize)]
-pub struct Mint1 {
-    pub to: Account,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
-}
-
-#[derive(CandidType, candid::Deserialize)]
-pub struct Transfer {
-    pub to: Account,
-    pub fee: Option<candid::Nat>,
-    pub from: Account,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
-}
-
-#[derive(CandidType, candid::Deserialize)]
-pub struct Transaction1 {
-    pub burn: Option<Burn>,
-    pub kind: String,
-    pub mint: Option<Mint1>,
-    pub timestamp: u64,
-    pub index: candid::Nat,
-    pub transfer: Option<Transfer>,
-}
-
 async fn fetch_block(block_height: candid::Nat) -> Result<Transaction1, String> {
-    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
-        ::call_raw(
-            Principal::from_text(WINDOGE_LEDGER_ID).unwrap(),
-            "get_transaction",
-            candid::encode_args((block_height,)).unwrap(),
-            0
-        ).await
-        .map_err(|(code, msg)| (code as i32, msg));
-    match result {
-        Ok(res) => {
-            let block = Decode!(&res, Option<Transaction1>).map_err(|e| format!("{:?}", e))?;
-            if let Some(block) = block {
-                ic_cdk::println!("transaction: {:?}", block.index);
-                Ok(block)
-            } else {
-                Err("Block not found".to_string())
-            }
-        }
-        Err((code, msg)) =>
-            Err(format!("Error while calling minter canister ({}): {:?}", code, msg)),
-    }
-}
-
-#[derive(CandidType, candid::Deserialize)]
-struct TransferArg {
-    pub to: Account,
-    pub fee: Option<candid::Nat>,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub from_subaccount: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
-}
-
-#[derive(CandidType, candid::Deserialize, Debug)]
-enum TransferError {
-    GenericError {
-        message: String,
-        error_code: candid::Nat,
-    },
-    TemporarilyUnavailable,
-    BadBurn {
-        min_burn_amount: candid::Nat,
-    },
-    Duplicate {
-        duplicate_of: candid::Nat,
-    },
-    BadFee {
-        expected_fee: candid::Nat,
-    },
-    CreatedInFuture {
-        ledger_time: u64,
-    },
-    TooOld,
-    InsufficientFunds {
-        balance: candid::Nat,
-    },
-}
-
-#[derive(CandidType, candid::Deserialize)]
-enum Result_ {
-    Ok(candid::Nat),
-    Err(TransferError),
-}
-
-async fn icrc1_transfer(transfer: TransferArg, token: Principal) -> Result<candid::Nat, String> {
-    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
-        ::call_raw(token, "icrc1_transfer", candid::encode_args((transfer,)).unwrap(), 0).await
-        .map_err(|(code, msg)| (code as i32, msg));
-    match result {
-        Ok(res) => {
-            let response = Decode!(&res, Result_).map_err(|e| format!("{:?}", e))?;
-            match response {
-                Result_::Ok(index) => Ok(index),
-                Result_::Err(e) => Err(format!("{:?}", e)),
-            }
-        }
-        Err((code, msg)) => Err(format!("Error icrc1_transfer ({}): {:?}", code, msg)),
-    }
+    get_transaction(Principal::from_text(WINDOGE_LEDGER_ID).unwrap(), block_height).await
 }
 
 #[update(hidden = true)]
@@ -702,76 +1280,9 @@ async fn transfer_exe(amount: u64) -> Result<candid::Nat, String> {
         amount: candid::Nat::from(amount),
     };
 
-    match icrc1_transfer(transfer, Principal::from_text(WINDOGE_LEDGER_ID).unwrap()).await {
+    match icrc1_transfer(transfer, Principal::from_text(WINDOGE_LEDGER_ID).unwrap()).await? {
         Ok(index) => Ok(index),
-        Err(e) => Err(e),
-    }
-}
-
-type Balance = candid::Nat;
-type Subaccount = serde_bytes::ByteBuf;
-#[derive(CandidType, candid::Deserialize)]
-struct BurnArgs {
-    memo: Option<serde_bytes::ByteBuf>,
-    from_subaccount: Option<Subaccount>,
-    created_at_time: Option<u64>,
-    amount: Balance,
-}
-
-type TxIndex = candid::Nat;
-type Timestamp = u64;
-#[derive(CandidType, candid::Deserialize, Debug)]
-pub enum TransferError1 {
-    GenericError {
-        message: String,
-        error_code: candid::Nat,
-    },
-    TemporarilyUnavailable,
-    BadBurn {
-        min_burn_amount: Balance,
-    },
-    Duplicate {
-        duplicate_of: TxIndex,
-    },
-    BadFee {
-        expected_fee: Balance,
-    },
-    CreatedInFuture {
-        ledger_time: Timestamp,
-    },
-    TooOld,
-    InsufficientFunds {
-        balance: Balance,
-    },
-}
-
-#[derive(CandidType, candid::Deserialize)]
-pub enum TransferResult {
-    Ok(TxIndex),
-    Err(TransferError1),
-}
-
-async fn burn_exe(args: BurnArgs) -> Result<candid::Nat, String> {
-    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
-        ::call_raw(
-            Principal::from_text(WINDOGE_LEDGER_ID).unwrap(),
-            "burn",
-            candid::encode_args((args,)).unwrap(),
-            0
-        ).await
-        .map_err(|(code, msg)| (code as i32, msg));
-    match result {
-        Ok(res) => {
-            let result = Decode!(&res, TransferResult).map_err(|e| format!("{:?}", e))?;
-            match result {
-                TransferResult::Ok(index) => {
-                    return Ok(index);
-                }
-                TransferResult::Err(e) => Err(format!("burn: {:?}", e)),
-            }
-        }
-        Err((code, msg)) =>
-            Err(format!("Error while calling minter canister ({}): {:?}", code, msg)),
+        Err(e) => Err(format!("{:?}", e)),
     }
 }
 
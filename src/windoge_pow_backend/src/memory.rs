@@ -12,177 +12,45 @@ use ic_stable_structures::{
     StableCell,
     StableVec,
 };
-use rapidhash::RapidHasher;
 use serde::{ Deserialize, Serialize };
+use serde_bytes::ByteBuf;
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::hash::Hasher;
-use crate::State;
-
-pub type Hash = u128; // 128-bit hash
+use crate::audit::AuditReport;
+use crate::backfill::Backfill;
+use crate::deposits::{ Deposit, DepositSource, PendingDeposit };
+use crate::mempool::{ DropReason, MempoolConfig };
+use crate::provisioning::Provisioning;
+use crate::retarget::RetargetConfig;
+use crate::withdrawals::{ Withdrawal, WithdrawalStatus };
+use crate::{ EmptyBlockConfig, State };
+
+pub use windoge_protocol::{
+    Account,
+    BatchRef,
+    Block,
+    BlockHeader,
+    Hash,
+    Stats,
+    Transaction,
+    TransactionKind,
+    BLOCK_VERSION,
+    MAX_MEMO_LEN,
+    SUBACCOUNT_LEN,
+};
 
 #[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct TransactionArgs {
     pub recipient: Principal,
+    /// Subaccount of `recipient` to credit, the default one if `None`.
+    pub to_subaccount: Option<ByteBuf>,
+    /// Subaccount of the caller to debit, the default one if `None`.
+    pub from_subaccount: Option<ByteBuf>,
     pub amount: u64,
-}
-
-#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
-pub struct Transaction {
-    pub sender: Principal,
-    pub recipient: Principal,
-    pub amount: u64,
-    pub timestamp: u64,
-}
-
-#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
-pub struct BlockHeader {
-    pub version: u32,
-    pub height: u64,
-    pub prev_hash: Hash,
-    pub merkle_root: Hash,
-    pub timestamp: u64,
-    pub difficulty: u32,
-}
-
-#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
-pub struct Block {
-    pub header: BlockHeader,
-    pub transactions: Vec<Transaction>,
-    pub nonce: u128,
-    pub hash: Hash,
-}
-
-impl Block {
-    pub fn new(
-        prev_block: &Block,
-        transactions: Vec<Transaction>,
-        difficulty: u32
-    ) -> Result<Self, String> {
-        let merkle_root = Block::calculate_merkle_root(&transactions);
-
-        let header = BlockHeader {
-            version: 1,
-            height: prev_block.header.height + 1,
-            prev_hash: prev_block.hash,
-            merkle_root,
-            timestamp: ic_cdk::api::time(),
-            difficulty,
-        };
-
-        let mut block = Self {
-            header,
-            transactions,
-            nonce: 0,
-            hash: 0,
-        };
-
-        block.calculate_block_hash();
-
-        Ok(block)
-    }
-
-    pub fn hash_transaction(transaction: &Transaction) -> Hash {
-        let tx_string = format!(
-            "{}{}{}",
-            transaction.sender,
-            transaction.recipient,
-            transaction.amount
-        );
-
-        let mut hasher = RapidHasher::new(0);
-        hasher.write(tx_string.as_bytes());
-        let hash64 = hasher.finish();
-
-        let hash128_high = {
-            let mut hasher = RapidHasher::new(hash64);
-            hasher.write(&hash64.to_le_bytes());
-            hasher.finish()
-        };
-
-        ((hash128_high as u128) << 64) | (hash64 as u128)
-    }
-
-    pub fn calculate_merkle_root(transactions: &[Transaction]) -> Hash {
-        if transactions.is_empty() {
-            return 0;
-        }
-
-        let mut hashes: Vec<Hash> = transactions
-            .iter()
-            .map(|tx| Block::hash_transaction(tx))
-            .collect();
-
-        while hashes.len() > 1 {
-            if hashes.len() % 2 != 0 {
-                hashes.push(hashes.last().unwrap().clone());
-            }
-
-            hashes = hashes
-                .chunks(2)
-                .map(|chunk| {
-                    let mut hasher = RapidHasher::new(0);
-                    hasher.write(&chunk[0].to_le_bytes());
-                    hasher.write(&chunk[1].to_le_bytes());
-                    let hash64 = hasher.finish();
-
-                    let hash128_high = {
-                        let mut hasher = RapidHasher::new(hash64);
-                        hasher.write(&hash64.to_le_bytes());
-                        hasher.finish()
-                    };
-
-                    ((hash128_high as u128) << 64) | (hash64 as u128)
-                })
-                .collect();
-        }
-
-        hashes[0]
-    }
-
-    pub fn calculate_block_hash(&mut self) {
-        let mut hasher = RapidHasher::new(0);
-        hasher.write(&self.header.version.to_le_bytes());
-        hasher.write(&self.header.height.to_le_bytes());
-        hasher.write(&self.header.prev_hash.to_le_bytes());
-        hasher.write(&self.header.merkle_root.to_le_bytes());
-        hasher.write(&self.header.timestamp.to_le_bytes());
-        hasher.write(&self.header.difficulty.to_le_bytes());
-        hasher.write(&self.nonce.to_le_bytes());
-
-        let hash64 = hasher.finish();
-        let hash128_high = {
-            let mut hasher = RapidHasher::new(hash64);
-            hasher.write(&hash64.to_le_bytes());
-            hasher.finish()
-        };
-
-        self.hash = ((hash128_high as u128) << 64) | (hash64 as u128);
-    }
-
-    pub fn genesis() -> Self {
-        Self {
-            header: BlockHeader {
-                version: 1,
-                height: 0,
-                prev_hash: 0,
-                merkle_root: 0,
-                timestamp: 0,
-                difficulty: 0,
-            },
-            transactions: vec![],
-            nonce: 0,
-            hash: 0,
-        }
-    }
-}
-
-#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
-pub struct Stats {
-    pub cycles_burned: u64,
-    pub timestamp: u64,
-    pub solve_time: u64,
-    pub miner: Principal,
+    pub fee: Option<u64>,
+    /// Defaults to one more than the latest nonce of the caller.
+    pub nonce: Option<u64>,
+    pub memo: Option<ByteBuf>,
 }
 
 #[derive(Default, Ord, PartialOrd, Clone, Eq, PartialEq)]
@@ -219,8 +87,36 @@ const DIFFICULTY_MEM_ID: MemoryId = MemoryId::new(13);
 const CURRENT_BLOCK_MEM_ID: MemoryId = MemoryId::new(14);
 const USER_TO_BLOCK_MINED_MEM_ID: MemoryId = MemoryId::new(15);
 const UPDATED_MINERS_MEM_ID: MemoryId = MemoryId::new(16);
+const TARGET_MEM_ID: MemoryId = MemoryId::new(17);
+const RETARGET_CONFIG_MEM_ID: MemoryId = MemoryId::new(18);
+const BLOCK_ACCEPTED_AT_MEM_ID: MemoryId = MemoryId::new(19);
+const EMPTY_BLOCK_CONFIG_MEM_ID: MemoryId = MemoryId::new(20);
+const AUDIT_REPORT_MEM_ID: MemoryId = MemoryId::new(21);
+const MINTED_MEM_ID: MemoryId = MemoryId::new(22);
+const WITHDRAWN_MEM_ID: MemoryId = MemoryId::new(23);
+const MEMPOOL_CONFIG_MEM_ID: MemoryId = MemoryId::new(24);
+const SENDER_NONCE_MEM_ID: MemoryId = MemoryId::new(25);
+const MEMPOOL_MEM_ID: MemoryId = MemoryId::new(26);
+const MEMO_INDEX_MEM_ID: MemoryId = MemoryId::new(27);
+const ACCOUNT_TO_BALANCE_MEM_ID: MemoryId = MemoryId::new(28);
+const TX_HEIGHT_MEM_ID: MemoryId = MemoryId::new(29);
+// 30 held withdrawal errors, superseded by WITHDRAWALS
+const ACCOUNT_TXS_MEM_ID: MemoryId = MemoryId::new(31);
+const WITHDRAWALS_MEM_ID: MemoryId = MemoryId::new(32);
+const WITHDRAWAL_QUEUE_MEM_ID: MemoryId = MemoryId::new(33);
+const DEPOSITS_MEM_ID: MemoryId = MemoryId::new(34);
+const DEPOSITED_MEM_ID: MemoryId = MemoryId::new(35);
+const PROVISIONINGS_MEM_ID: MemoryId = MemoryId::new(36);
+const PROVISIONING_QUEUE_MEM_ID: MemoryId = MemoryId::new(37);
+const DROPPED_MEM_ID: MemoryId = MemoryId::new(38);
+const BACKFILL_MEM_ID: MemoryId = MemoryId::new(39);
+const PENDING_DEPOSITS_MEM_ID: MemoryId = MemoryId::new(40);
 
 type VM = VirtualMemory<DefMem>;
+/// Memo, block height and position in the block.
+type MemoKey = Cbor<(ByteBuf, u64, u32)>;
+/// Account, block height and position in the block.
+type AccountTxKey = Cbor<(Account, u64, u32)>;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MM<DefaultMemoryImpl>> = RefCell::new(
@@ -233,10 +129,65 @@ thread_local! {
         RefCell::new(StableBTreeMap::init(mm.borrow().get(MINER_TO_OWNER_MEM_ID)))
     });
 
+    // balances from before subaccounts, emptied by `migrate_balances`
     static USER_TO_BALANCE: RefCell<StableBTreeMap<Principal, u64, VM>> = MEMORY_MANAGER.with(|mm| {
         RefCell::new(StableBTreeMap::init(mm.borrow().get(USER_TO_BALANCE_MEM_ID)))
     });
 
+    static ACCOUNT_TO_BALANCE: RefCell<StableBTreeMap<Cbor<Account>, u64, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(ACCOUNT_TO_BALANCE_MEM_ID)))
+    });
+
+    static TX_HEIGHT: RefCell<StableBTreeMap<Hash, u64, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(TX_HEIGHT_MEM_ID)))
+    });
+
+    // transactions and batches that left the mempool without being mined
+    static DROPPED: RefCell<StableBTreeMap<Hash, Cbor<DropReason>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(DROPPED_MEM_ID)))
+    });
+
+    static WITHDRAWALS: RefCell<StableBTreeMap<Hash, Cbor<Withdrawal>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(WITHDRAWALS_MEM_ID)))
+    });
+
+    // keyed by the index of the deposit on the BIL ledger
+    static DEPOSITS: RefCell<StableBTreeMap<u64, Cbor<Deposit>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(DEPOSITS_MEM_ID)))
+    });
+
+    // deposits whose burn hasn't settled yet
+    static PENDING_DEPOSITS: RefCell<StableBTreeMap<Cbor<DepositSource>, Cbor<PendingDeposit>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PENDING_DEPOSITS_MEM_ID)))
+    });
+
+    static DEPOSITED: RefCell<StableCell<u64, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(DEPOSITED_MEM_ID), 0_u64).expect(
+                "failed to initialize deposited supply"
+            )
+        )
+    });
+
+    // ids of the withdrawals in `WITHDRAWALS` that are still queued
+    static WITHDRAWAL_QUEUE: RefCell<StableBTreeMap<Hash, (), VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(WITHDRAWAL_QUEUE_MEM_ID)))
+    });
+
+    // keyed by the index of the EXE payment on the ledger
+    static PROVISIONINGS: RefCell<StableBTreeMap<u64, Cbor<Provisioning>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PROVISIONINGS_MEM_ID)))
+    });
+
+    // payment indices of the provisionings in `PROVISIONINGS` that are still running
+    static PROVISIONING_QUEUE: RefCell<StableBTreeMap<u64, (), VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PROVISIONING_QUEUE_MEM_ID)))
+    });
+
+    static ACCOUNT_TXS: RefCell<StableBTreeMap<AccountTxKey, (), VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(ACCOUNT_TXS_MEM_ID)))
+    });
+
     static TX_LOG: RefCell<StableLog<Cbor<u64>, VM, VM>> = MEMORY_MANAGER.with(|mm| {
         RefCell::new(
             StableLog::init(
@@ -320,6 +271,89 @@ thread_local! {
             )
         )
     });
+
+    static TARGET: RefCell<StableCell<Cbor<Hash>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(TARGET_MEM_ID), Cbor(0)).expect(
+                "failed to initialize target"
+            )
+        )
+    });
+
+    static RETARGET_CONFIG: RefCell<StableCell<Cbor<RetargetConfig>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(
+                mm.borrow().get(RETARGET_CONFIG_MEM_ID),
+                Cbor(RetargetConfig::default())
+            ).expect("failed to initialize retarget config")
+        )
+    });
+
+    static BLOCK_ACCEPTED_AT: RefCell<StableBTreeMap<u64, u64, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(BLOCK_ACCEPTED_AT_MEM_ID)))
+    });
+
+    static EMPTY_BLOCK_CONFIG: RefCell<StableCell<Cbor<EmptyBlockConfig>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(
+                mm.borrow().get(EMPTY_BLOCK_CONFIG_MEM_ID),
+                Cbor(EmptyBlockConfig::default())
+            ).expect("failed to initialize empty block config")
+        )
+    });
+
+    static AUDIT_REPORT: RefCell<StableCell<Cbor<Option<AuditReport>>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(AUDIT_REPORT_MEM_ID), Cbor(None)).expect(
+                "failed to initialize audit report"
+            )
+        )
+    });
+
+    static BACKFILL: RefCell<StableCell<Cbor<Option<Backfill>>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(BACKFILL_MEM_ID), Cbor(None)).expect(
+                "failed to initialize backfill"
+            )
+        )
+    });
+
+    static MINTED: RefCell<StableCell<Cbor<Option<u64>>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(MINTED_MEM_ID), Cbor(None)).expect(
+                "failed to initialize minted supply"
+            )
+        )
+    });
+
+    static WITHDRAWN: RefCell<StableCell<Cbor<Option<u64>>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(WITHDRAWN_MEM_ID), Cbor(None)).expect(
+                "failed to initialize withdrawn supply"
+            )
+        )
+    });
+
+    static MEMPOOL_CONFIG: RefCell<StableCell<Cbor<MempoolConfig>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(
+                mm.borrow().get(MEMPOOL_CONFIG_MEM_ID),
+                Cbor(MempoolConfig::default())
+            ).expect("failed to initialize mempool config")
+        )
+    });
+
+    static SENDER_NONCE: RefCell<StableBTreeMap<Principal, u64, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(SENDER_NONCE_MEM_ID)))
+    });
+
+    static MEMPOOL: RefCell<StableBTreeMap<u64, Cbor<Transaction>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(MEMPOOL_MEM_ID)))
+    });
+
+    static MEMO_INDEX: RefCell<StableBTreeMap<MemoKey, (), VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(MEMO_INDEX_MEM_ID)))
+    });
 }
 
 pub fn add_burned_exe(amount: u64) -> Result<u64, ValueError> {
@@ -331,6 +365,42 @@ pub fn get_burned_exe() -> u64 {
     BURNED_EXE.with(|s| *s.borrow().get())
 }
 
+/// Rewards credited so far, `None` until the total has been seeded.
+pub fn minted() -> Option<u64> {
+    MINTED.with(|s| s.borrow().get().0)
+}
+
+pub fn set_minted(amount: u64) -> Result<(), ValueError> {
+    MINTED.with(|s| s.borrow_mut().set(Cbor(Some(amount))).map(|_| ()))
+}
+
+pub fn add_minted(amount: u64) -> Result<(), ValueError> {
+    set_minted(minted().unwrap_or(0) + amount)
+}
+
+/// Amount withdrawn to the BIL ledger, `None` until the total has been seeded.
+pub fn withdrawn() -> Option<u64> {
+    WITHDRAWN.with(|s| s.borrow().get().0)
+}
+
+pub fn set_withdrawn(amount: u64) -> Result<(), ValueError> {
+    WITHDRAWN.with(|s| s.borrow_mut().set(Cbor(Some(amount))).map(|_| ()))
+}
+
+pub fn add_withdrawn(amount: u64) -> Result<(), ValueError> {
+    set_withdrawn(withdrawn().unwrap_or(0) + amount)
+}
+
+/// Amount deposited from the BIL ledger.
+pub fn deposited() -> u64 {
+    DEPOSITED.with(|s| *s.borrow().get())
+}
+
+pub fn add_deposited(amount: u64) -> Result<u64, ValueError> {
+    let current = deposited();
+    DEPOSITED.with(|s| s.borrow_mut().set(current + amount))
+}
+
 pub fn update_transaction_count(amount: u64) -> Result<u64, ValueError> {
     let current = get_transaction_count();
     TRANSACTION_COUNT.with(|s| s.borrow_mut().set(current + amount))
@@ -356,6 +426,57 @@ pub fn difficulty() -> u64 {
     DIFFICULTY.with(|s| *s.borrow().get())
 }
 
+pub fn update_target(target: Hash) -> Result<Hash, ValueError> {
+    TARGET.with(|s| s.borrow_mut().set(Cbor(target)).map(|previous| previous.0))
+}
+
+pub fn target() -> Hash {
+    TARGET.with(|s| s.borrow().get().0)
+}
+
+pub fn update_retarget_config(config: RetargetConfig) -> Result<RetargetConfig, ValueError> {
+    RETARGET_CONFIG.with(|s| s.borrow_mut().set(Cbor(config)).map(|previous| previous.0))
+}
+
+pub fn retarget_config() -> RetargetConfig {
+    RETARGET_CONFIG.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn update_empty_block_config(
+    config: EmptyBlockConfig
+) -> Result<EmptyBlockConfig, ValueError> {
+    EMPTY_BLOCK_CONFIG.with(|s| s.borrow_mut().set(Cbor(config)).map(|previous| previous.0))
+}
+
+pub fn empty_block_config() -> EmptyBlockConfig {
+    EMPTY_BLOCK_CONFIG.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn update_audit_report(report: AuditReport) -> Result<(), ValueError> {
+    AUDIT_REPORT.with(|s| s.borrow_mut().set(Cbor(Some(report))).map(|_| ()))
+}
+
+pub fn audit_report() -> Option<AuditReport> {
+    AUDIT_REPORT.with(|s| s.borrow().get().0.clone())
+}
+
+/// Walk over the stored chain still running, if any.
+pub fn backfill() -> Option<Backfill> {
+    BACKFILL.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn update_backfill(backfill: Option<Backfill>) -> Result<(), ValueError> {
+    BACKFILL.with(|s| s.borrow_mut().set(Cbor(backfill)).map(|_| ()))
+}
+
+pub fn update_mempool_config(config: MempoolConfig) -> Result<MempoolConfig, ValueError> {
+    MEMPOOL_CONFIG.with(|s| s.borrow_mut().set(Cbor(config)).map(|previous| previous.0))
+}
+
+pub fn mempool_config() -> MempoolConfig {
+    MEMPOOL_CONFIG.with(|s| s.borrow().get().0.clone())
+}
+
 pub fn current_block() -> Vec<Block> {
     CURRENT_BLOCK.with(|s|
         s
@@ -373,6 +494,10 @@ pub fn update_current_block(block: Block) {
     });
 }
 
+pub fn clear_current_block() {
+    CURRENT_BLOCK.with(|s| s.borrow_mut().clear_new());
+}
+
 pub fn get_users_to_block_mined() -> Vec<(Principal, u64)> {
     USER_TO_BLOCK_MINED.with(|s| s.borrow().iter().collect())
 }
@@ -434,6 +559,29 @@ pub fn get_block(index: u64) -> Option<Block> {
     )
 }
 
+pub fn insert_accepted_at(height: u64, accepted_at: u64) {
+    BLOCK_ACCEPTED_AT.with(|s| s.borrow_mut().insert(height, accepted_at));
+}
+
+pub fn get_accepted_at(height: u64) -> Option<u64> {
+    BLOCK_ACCEPTED_AT.with(|s| s.borrow().get(&height))
+}
+
+/// Time between publishing the template and accepting the solution, for the
+/// most recent `count` blocks that have an acceptance time on record.
+pub fn recent_block_times(count: u64) -> Vec<u64> {
+    BLOCK_ACCEPTED_AT.with(|s| {
+        s.borrow()
+            .iter()
+            .rev()
+            .take(count as usize)
+            .filter_map(|(height, accepted_at)| {
+                get_block(height).map(|block| accepted_at.saturating_sub(block.header.timestamp))
+            })
+            .collect()
+    })
+}
+
 pub fn insert_new_transaction(block: u64) -> Result<u64, WriteError> {
     TX_LOG.with(|s| s.borrow_mut().append(&Cbor(block)))
 }
@@ -448,19 +596,6 @@ pub fn get_all_transactions() -> Vec<u64> {
     )
 }
 
-pub fn get_transaction(index: u64) -> Option<u64> {
-    TX_LOG.with(|s|
-        s
-            .borrow()
-            .get(index)
-            .map(|b| b.0)
-    )
-}
-
-pub fn transaction_count() -> u64 {
-    TX_LOG.with(|s| s.borrow().len())
-}
-
 pub fn insert_new_miner(miner: Principal, owner: Principal, block_index: u64) {
     MINER_TO_OWNER.with(|s| s.borrow_mut().insert(miner, (owner, block_index)));
 }
@@ -482,22 +617,272 @@ pub fn get_miner_to_owner_and_index() -> Vec<(Principal, (Principal, u64))> {
     MINER_TO_OWNER.with(|s| s.borrow().iter().collect())
 }
 
-pub fn add_balance(user: Principal, amount: u64) {
-    USER_TO_BALANCE.with(|s| {
-        let new_balance = s.borrow().get(&user).unwrap_or(0) + amount;
-        s.borrow_mut().insert(user, new_balance);
+pub fn add_balance(account: &Account, amount: u64) {
+    let key = Cbor(account.clone());
+    ACCOUNT_TO_BALANCE.with(|s| {
+        let new_balance = s.borrow().get(&key).unwrap_or(0) + amount;
+        s.borrow_mut().insert(key, new_balance);
     });
 }
 
-pub fn sub_balance(user: Principal, amount: u64) {
-    USER_TO_BALANCE.with(|s| {
-        let new_balance = s.borrow().get(&user).unwrap_or(0).saturating_sub(amount);
-        s.borrow_mut().insert(user, new_balance);
+pub fn sub_balance(account: &Account, amount: u64) {
+    let key = Cbor(account.clone());
+    ACCOUNT_TO_BALANCE.with(|s| {
+        let new_balance = s.borrow().get(&key).unwrap_or(0).saturating_sub(amount);
+        s.borrow_mut().insert(key, new_balance);
+    });
+}
+
+pub fn get_balance(account: &Account) -> u64 {
+    ACCOUNT_TO_BALANCE.with(|s| s.borrow().get(&Cbor(account.clone())).unwrap_or(0))
+}
+
+/// Moves the principal balances kept before subaccounts to the default
+/// subaccount of each principal. Returns the number of balances moved.
+pub fn migrate_balances() -> u64 {
+    let balances: Vec<(Principal, u64)> = USER_TO_BALANCE.with(|s| s.borrow().iter().collect());
+    for (principal, balance) in &balances {
+        add_balance(&Account::from(*principal), *balance);
+    }
+    USER_TO_BALANCE.with(|s| s.borrow_mut().clear_new());
+    balances.len() as u64
+}
+
+/// Nonce of the latest transaction of `sender` included in a block.
+pub fn get_nonce(sender: Principal) -> u64 {
+    SENDER_NONCE.with(|s| s.borrow().get(&sender).unwrap_or(0))
+}
+
+pub fn update_nonce(sender: Principal, nonce: u64) {
+    SENDER_NONCE.with(|s| s.borrow_mut().insert(sender, nonce));
+}
+
+/// Stable copy of the mempool, keyed by arrival.
+pub fn insert_mempool_transaction(transaction: Transaction) {
+    MEMPOOL.with(|s| {
+        let next = s
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(key, _)| key + 1);
+        s.borrow_mut().insert(next, Cbor(transaction));
     });
 }
 
-pub fn get_balance(user: Principal) -> u64 {
-    USER_TO_BALANCE.with(|s| s.borrow().get(&user).unwrap_or(0))
+fn mempool_key(tx_id: Hash) -> Option<u64> {
+    MEMPOOL.with(|s|
+        s
+            .borrow()
+            .iter()
+            .find(|(_, tx)| tx.0.id() == tx_id)
+            .map(|(key, _)| key)
+    )
+}
+
+pub fn remove_mempool_transaction(tx_id: Hash) {
+    if let Some(key) = mempool_key(tx_id) {
+        MEMPOOL.with(|s| s.borrow_mut().remove(&key));
+    }
+}
+
+/// Replaces the transaction `tx_id`, keeping its place in the arrival order.
+pub fn replace_mempool_transaction(tx_id: Hash, transaction: Transaction) {
+    if let Some(key) = mempool_key(tx_id) {
+        MEMPOOL.with(|s| s.borrow_mut().insert(key, Cbor(transaction)));
+    }
+}
+
+pub fn mempool_transactions() -> Vec<Transaction> {
+    MEMPOOL.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(_, tx)| tx.0)
+            .collect()
+    )
+}
+
+pub fn insert_memo_index(memo: ByteBuf, height: u64, position: u32) {
+    MEMO_INDEX.with(|s| s.borrow_mut().insert(Cbor((memo, height, position)), ()));
+}
+
+/// Block height and position of the latest `limit` transactions with `memo`,
+/// newest first.
+pub fn memo_locations(memo: &ByteBuf, limit: usize) -> Vec<(u64, u32)> {
+    let start = Cbor((memo.clone(), 0, 0));
+    let end = Cbor((memo.clone(), u64::MAX, u32::MAX));
+    MEMO_INDEX.with(|s|
+        s
+            .borrow()
+            .range(start..=end)
+            .rev()
+            .take(limit)
+            .map(|(key, _)| (key.0.1, key.0.2))
+            .collect()
+    )
+}
+
+pub fn insert_account_tx(account: Account, height: u64, position: u32) {
+    ACCOUNT_TXS.with(|s| s.borrow_mut().insert(Cbor((account, height, position)), ()));
+}
+
+/// Block height and position of up to `limit` transactions of `account`,
+/// newest first, skipping the `start` newest ones.
+pub fn account_tx_locations(account: &Account, start: usize, limit: usize) -> Vec<(u64, u32)> {
+    let first = Cbor((account.clone(), 0, 0));
+    let last = Cbor((account.clone(), u64::MAX, u32::MAX));
+    ACCOUNT_TXS.with(|s|
+        s
+            .borrow()
+            .range(first..=last)
+            .rev()
+            .skip(start)
+            .take(limit)
+            .map(|(key, _)| (key.0.1, key.0.2))
+            .collect()
+    )
+}
+
+pub fn account_tx_count() -> u64 {
+    ACCOUNT_TXS.with(|s| s.borrow().len())
+}
+
+pub fn insert_tx_height(tx_id: Hash, height: u64) {
+    TX_HEIGHT.with(|s| s.borrow_mut().insert(tx_id, height));
+}
+
+/// Height of the block that includes the transaction `tx_id`.
+pub fn get_tx_height(tx_id: Hash) -> Option<u64> {
+    TX_HEIGHT.with(|s| s.borrow().get(&tx_id))
+}
+
+pub fn tx_height_count() -> u64 {
+    TX_HEIGHT.with(|s| s.borrow().len())
+}
+
+pub fn insert_dropped(tx_id: Hash, reason: DropReason) {
+    DROPPED.with(|s| s.borrow_mut().insert(tx_id, Cbor(reason)));
+}
+
+pub fn get_dropped(tx_id: Hash) -> Option<DropReason> {
+    DROPPED.with(|s| s.borrow().get(&tx_id).map(|r| r.0))
+}
+
+/// Stores `withdrawal`, keeping the queue in step with its status.
+pub fn update_withdrawal(withdrawal: Withdrawal) {
+    let tx_id = withdrawal.tx_id;
+    WITHDRAWAL_QUEUE.with(|s| {
+        if withdrawal.status == WithdrawalStatus::Queued {
+            s.borrow_mut().insert(tx_id, ());
+        } else {
+            s.borrow_mut().remove(&tx_id);
+        }
+    });
+    WITHDRAWALS.with(|s| s.borrow_mut().insert(tx_id, Cbor(withdrawal)));
+}
+
+pub fn get_withdrawal(tx_id: Hash) -> Option<Withdrawal> {
+    WITHDRAWALS.with(|s| s.borrow().get(&tx_id).map(|w| w.0))
+}
+
+pub fn update_provisioning(provisioning: Provisioning) {
+    let block_index = provisioning.block_index;
+    PROVISIONING_QUEUE.with(|s| {
+        if provisioning.status.is_running() {
+            s.borrow_mut().insert(block_index, ());
+        } else {
+            s.borrow_mut().remove(&block_index);
+        }
+    });
+    PROVISIONINGS.with(|s| s.borrow_mut().insert(block_index, Cbor(provisioning)));
+}
+
+pub fn get_provisioning(block_index: u64) -> Option<Provisioning> {
+    PROVISIONINGS.with(|s| s.borrow().get(&block_index).map(|p| p.0))
+}
+
+pub fn running_provisionings() -> Vec<u64> {
+    PROVISIONING_QUEUE.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(block_index, _)| block_index)
+            .collect()
+    )
+}
+
+pub fn insert_deposit(deposit: Deposit) {
+    DEPOSITS.with(|s| s.borrow_mut().insert(deposit.block_index, Cbor(deposit)));
+}
+
+pub fn get_deposit(block_index: u64) -> Option<Deposit> {
+    DEPOSITS.with(|s| s.borrow().get(&block_index).map(|d| d.0))
+}
+
+pub fn all_deposits() -> Vec<Deposit> {
+    DEPOSITS.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(_, d)| d.0)
+            .collect()
+    )
+}
+
+pub fn deposit_count() -> u64 {
+    DEPOSITS.with(|s| s.borrow().len())
+}
+
+pub fn insert_pending_deposit(pending: PendingDeposit) {
+    PENDING_DEPOSITS.with(|s| s.borrow_mut().insert(Cbor(pending.source.clone()), Cbor(pending)));
+}
+
+pub fn get_pending_deposit(source: &DepositSource) -> Option<PendingDeposit> {
+    PENDING_DEPOSITS.with(|s| s.borrow().get(&Cbor(source.clone())).map(|p| p.0))
+}
+
+pub fn remove_pending_deposit(source: &DepositSource) {
+    PENDING_DEPOSITS.with(|s| s.borrow_mut().remove(&Cbor(source.clone())));
+}
+
+pub fn pending_deposits() -> Vec<PendingDeposit> {
+    PENDING_DEPOSITS.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(_, p)| p.0)
+            .collect()
+    )
+}
+
+pub fn queued_withdrawals() -> Vec<Withdrawal> {
+    WITHDRAWAL_QUEUE.with(|s|
+        s
+            .borrow()
+            .iter()
+            .filter_map(|(tx_id, _)| get_withdrawal(tx_id))
+            .collect()
+    )
+}
+
+/// Up to `limit` balances in account order, starting after `after`.
+pub fn balances_after(after: Option<Account>, limit: usize) -> Vec<(Account, u64)> {
+    ACCOUNT_TO_BALANCE.with(|s| {
+        let s = s.borrow();
+        match after {
+            Some(after) =>
+                s
+                    .range((std::ops::Bound::Excluded(Cbor(after)), std::ops::Bound::Unbounded))
+                    .take(limit)
+                    .map(|(account, balance)| (account.0, balance))
+                    .collect(),
+            None =>
+                s
+                    .iter()
+                    .take(limit)
+                    .map(|(account, balance)| (account.0, balance))
+                    .collect(),
+        }
+    })
 }
 
 pub fn insert_stats(stats: Stats) -> Result<u64, WriteError> {
@@ -0,0 +1,451 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use std::cmp::Reverse;
+use std::collections::{ BTreeMap, BinaryHeap, VecDeque };
+use crate::memory::{
+    get_balance,
+    get_nonce,
+    get_tx_height,
+    insert_dropped,
+    insert_mempool_transaction,
+    mempool_transactions,
+    remove_mempool_transaction,
+    replace_mempool_transaction,
+    Hash,
+    Transaction,
+};
+use crate::State;
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MempoolConfig {
+    /// Most transactions included in one block template, besides the coinbase.
+    pub max_block_transactions: u64,
+    /// Most transactions waiting in the mempool. Beyond it the transaction
+    /// with the lowest fee is evicted.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Most transactions one sender may have waiting.
+    #[serde(default = "default_max_per_sender")]
+    pub max_per_sender: u64,
+    /// Seconds a transaction may wait before it expires.
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+fn default_max_size() -> u64 {
+    150
+}
+
+fn default_max_per_sender() -> u64 {
+    25
+}
+
+fn default_ttl_secs() -> u64 {
+    3_600
+}
+
+/// Why a transaction left the mempool without being mined.
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum DropReason {
+    Expired,
+    /// Evicted by a transaction paying a higher fee.
+    Evicted,
+    Cancelled,
+    Replaced,
+    /// The sender could no longer pay for it after an upgrade.
+    Invalidated,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_block_transactions: 100,
+            max_size: default_max_size(),
+            max_per_sender: default_max_per_sender(),
+            ttl_secs: default_ttl_secs(),
+        }
+    }
+}
+
+impl MempoolConfig {
+    /// Rejects limits that would stop every template or transaction.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_block_transactions == 0 {
+            return Err("max_block_transactions must be positive".to_string());
+        }
+        if self.max_size == 0 || self.max_per_sender == 0 {
+            return Err("max_size and max_per_sender must be positive".to_string());
+        }
+        if self.ttl_secs == 0 {
+            return Err("ttl_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Whether `tx` is the transaction `tx_id` or part of the batch `tx_id`.
+pub fn has_id(tx: &Transaction, tx_id: Hash) -> bool {
+    tx.id() == tx_id || tx.batch.is_some_and(|batch| batch.id == tx_id)
+}
+
+/// Whether the published template includes the transaction or batch `tx_id`.
+pub fn in_template(state: &State, tx_id: Hash) -> bool {
+    state.current_block
+        .as_ref()
+        .is_some_and(|block| block.transactions.iter().any(|tx| has_id(tx, tx_id)))
+}
+
+/// Adds `transactions` to the mempool, all or none of them. Ids already
+/// pending or mined are rejected. When the mempool is full, the lowest fee
+/// transactions outside the published template and outside any batch are
+/// evicted to make room. The caller reserves the pending balance once they
+/// are admitted.
+pub fn admit(state: &mut State, transactions: Vec<Transaction>) -> Result<(), String> {
+    let Some(first) = transactions.first() else {
+        return Ok(());
+    };
+    let config = &state.mempool_config;
+    let count = transactions.len() as u64;
+    let fee = transactions
+        .iter()
+        .map(|tx| tx.fee)
+        .sum::<u64>() / count;
+
+    for tx in &transactions {
+        let ids = std::iter::once(tx.id()).chain(tx.batch.map(|batch| batch.id));
+        for tx_id in ids {
+            let pending = state.mempool.iter().any(|pending| has_id(pending, tx_id));
+            if pending || get_tx_height(tx_id).is_some() {
+                return Err("transaction already exists".to_string());
+            }
+        }
+    }
+
+    let from_sender = state.mempool
+        .iter()
+        .filter(|tx| tx.sender == first.sender)
+        .count() as u64;
+    if from_sender + count > config.max_per_sender {
+        return Err("too many pending transactions".to_string());
+    }
+
+    let overflow = ((state.mempool.len() as u64) + count).saturating_sub(config.max_size);
+    if overflow > 0 {
+        // the newest of the cheapest transactions go first
+        let mut candidates: Vec<(usize, u64)> = state.mempool
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.batch.is_none() && !in_template(state, tx.id()))
+            .map(|(index, tx)| (index, tx.fee))
+            .collect();
+        candidates.sort_by_key(|(index, fee)| (*fee, Reverse(*index)));
+        candidates.truncate(overflow as usize);
+
+        if (candidates.len() as u64) < overflow || candidates.iter().any(|(_, f)| *f >= fee) {
+            return Err(
+                "network is congested, transactions can be processed in next block".to_string()
+            );
+        }
+
+        candidates.sort_by_key(|(index, _)| Reverse(*index));
+        for (index, _) in candidates {
+            discard(state, index, DropReason::Evicted);
+        }
+    }
+
+    for transaction in transactions {
+        insert_mempool_transaction(transaction.clone());
+        state.mempool.push(transaction);
+    }
+    Ok(())
+}
+
+/// Replaces the transaction at `index`. The caller adjusts the pending
+/// balance of the sender.
+pub fn replace(state: &mut State, index: usize, transaction: Transaction) {
+    insert_dropped(state.mempool[index].id(), DropReason::Replaced);
+    replace_mempool_transaction(state.mempool[index].id(), transaction.clone());
+    state.mempool[index] = transaction;
+}
+
+/// Removes the transaction at `index` and releases its pending balance.
+pub fn remove(state: &mut State, index: usize) -> Transaction {
+    let transaction = state.mempool.remove(index);
+    remove_mempool_transaction(transaction.id());
+    state.pending_balance.entry(transaction.from()).and_modify(|e| {
+        *e = e.saturating_sub(transaction.amount + transaction.fee);
+    });
+    transaction
+}
+
+/// Removes the transaction at `index` without mining it, and records why.
+pub fn discard(state: &mut State, index: usize, reason: DropReason) -> Transaction {
+    let transaction = remove(state, index);
+    insert_dropped(transaction.id(), reason);
+    if let Some(batch) = transaction.batch {
+        insert_dropped(batch.id, reason);
+    }
+    transaction
+}
+
+/// Discards every transaction of the batch `batch_id`.
+pub fn discard_batch(state: &mut State, batch_id: Hash, reason: DropReason) -> Vec<Transaction> {
+    let mut removed = Vec::new();
+    while
+        let Some(index) = state.mempool
+            .iter()
+            .position(|tx| tx.batch.is_some_and(|batch| batch.id == batch_id))
+    {
+        removed.push(discard(state, index, reason));
+    }
+    removed
+}
+
+/// Removes the transaction with id `tx_id`, if it is in the mempool.
+pub fn remove_by_id(state: &mut State, tx_id: Hash) -> Option<Transaction> {
+    let index = state.mempool.iter().position(|tx| tx.id() == tx_id)?;
+    Some(remove(state, index))
+}
+
+/// Removes transactions older than the configured TTL. Returns whether one of
+/// them was part of the published template, which then has to be replaced.
+pub fn expire(state: &mut State, now: u64) -> bool {
+    let ttl = state.mempool_config.ttl_secs.saturating_mul(crate::SEC_NANOS);
+    let expired: Vec<Hash> = state.mempool
+        .iter()
+        .filter(|tx| tx.timestamp.saturating_add(ttl) < now)
+        .map(|tx| tx.id())
+        .collect();
+
+    let mut stale_template = false;
+    for tx_id in expired {
+        stale_template |= in_template(state, tx_id);
+        if let Some(index) = state.mempool.iter().position(|tx| tx.id() == tx_id) {
+            discard(state, index, DropReason::Expired);
+        }
+        ic_cdk::println!("Transaction {} expired", tx_id);
+    }
+
+    stale_template
+}
+
+/// Restores the mempool from stable memory after an upgrade and reserves
+/// the pending balances again. Transactions the sender can no longer pay for
+/// are dropped.
+pub fn restore(state: &mut State) {
+    for transaction in mempool_transactions() {
+        let total = transaction.amount + transaction.fee;
+        let from = transaction.from();
+        let reserved = state.pending_balance.get(&from).copied().unwrap_or(0);
+
+        if
+            transaction.nonce <= get_nonce(transaction.sender) ||
+            get_balance(&from) < reserved + total
+        {
+            remove_mempool_transaction(transaction.id());
+            insert_dropped(transaction.id(), DropReason::Invalidated);
+            if let Some(batch) = transaction.batch {
+                insert_dropped(batch.id, DropReason::Invalidated);
+            }
+            ic_cdk::println!("Dropped transaction {} from the mempool on upgrade", transaction.id());
+            continue;
+        }
+
+        state.pending_balance
+            .entry(from)
+            .and_modify(|e| {
+                *e += total;
+            })
+            .or_insert(total);
+        state.mempool.push(transaction);
+    }
+
+    // a batch is only restored as a whole
+    let mut batches: BTreeMap<Hash, (u32, u32)> = BTreeMap::new();
+    for batch in state.mempool.iter().filter_map(|tx| tx.batch) {
+        batches.entry(batch.id).or_insert((batch.size, 0)).1 += 1;
+    }
+    for (batch_id, (size, restored)) in batches {
+        if restored < size {
+            discard_batch(state, batch_id, DropReason::Invalidated);
+            ic_cdk::println!("Dropped batch {} from the mempool on upgrade", batch_id);
+        }
+    }
+}
+
+/// Highest nonce `sender` has waiting in the mempool.
+pub fn pending_nonce(mempool: &[Transaction], sender: Principal) -> Option<u64> {
+    mempool
+        .iter()
+        .filter(|tx| tx.sender == sender)
+        .map(|tx| tx.nonce)
+        .max()
+}
+
+/// Next unit a sender's queue offers: a single transaction, or a whole batch.
+fn next_unit(queue: &VecDeque<(usize, &Transaction)>) -> Option<(usize, usize, u64)> {
+    let (index, tx) = queue.front()?;
+    let size = tx.batch.map_or(1, |batch| batch.size as usize);
+    let complete = queue
+        .iter()
+        .take(size)
+        .filter(|(_, member)| member.batch == tx.batch)
+        .count() == size;
+    if !complete {
+        return None;
+    }
+
+    let fee = queue
+        .iter()
+        .take(size)
+        .map(|(_, tx)| tx.fee)
+        .sum::<u64>() / (size as u64);
+    Some((*index, size, fee))
+}
+
+/// Transactions for the next template. Each sender's transactions are taken
+/// in nonce order, batches as a whole; among the next transaction or batch of
+/// every sender the highest fee per transaction goes first, and equal fees
+/// keep their arrival order.
+pub fn select_transactions(mempool: &[Transaction], config: &MempoolConfig) -> Vec<Transaction> {
+    let mut queues: BTreeMap<Principal, VecDeque<(usize, &Transaction)>> = BTreeMap::new();
+    for (index, tx) in mempool.iter().enumerate() {
+        queues.entry(tx.sender).or_default().push_back((index, tx));
+    }
+    for queue in queues.values_mut() {
+        queue.make_contiguous().sort_by_key(|(_, tx)| tx.nonce);
+    }
+
+    let mut heads: BinaryHeap<(u64, Reverse<usize>, Principal)> = queues
+        .iter()
+        .filter_map(|(sender, queue)| {
+            next_unit(queue).map(|(index, _, fee)| (fee, Reverse(index), *sender))
+        })
+        .collect();
+
+    let max = config.max_block_transactions as usize;
+    let mut selected = Vec::new();
+    while let Some((_, _, sender)) = heads.pop() {
+        let queue = queues.get_mut(&sender).unwrap();
+        let Some((_, size, _)) = next_unit(queue) else {
+            continue;
+        };
+
+        // later nonces of this sender can't skip a batch that doesn't fit
+        if selected.len() + size > max {
+            continue;
+        }
+        selected.extend(queue.drain(..size).map(|(_, tx)| tx.clone()));
+
+        if let Some((index, _, fee)) = next_unit(queue) {
+            heads.push((fee, Reverse(index), sender));
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{ get_dropped, BatchRef, Block };
+
+    fn tx(sender: u8, nonce: u64, fee: u64) -> Transaction {
+        Transaction {
+            sender: Principal::from_slice(&[sender]),
+            recipient: Principal::anonymous(),
+            amount: 1,
+            timestamp: 0,
+            kind: Default::default(),
+            fee,
+            nonce,
+            batch: None,
+            memo: None,
+            from_subaccount: None,
+            to_subaccount: None,
+        }
+    }
+
+    fn batched(mut transactions: Vec<Transaction>) -> Vec<Transaction> {
+        let batch = BatchRef {
+            id: windoge_protocol::batch_id(&transactions),
+            size: transactions.len() as u32,
+        };
+        for transaction in transactions.iter_mut() {
+            transaction.batch = Some(batch);
+        }
+        transactions
+    }
+
+    fn selected(mempool: &[Transaction], max_block_transactions: u64) -> Vec<(u8, u64)> {
+        let config = MempoolConfig { max_block_transactions, ..Default::default() };
+        select_transactions(mempool, &config)
+            .iter()
+            .map(|tx| (tx.sender.as_slice()[0], tx.nonce))
+            .collect()
+    }
+
+    fn state_with(mempool: Vec<Transaction>, max_size: u64) -> State {
+        let mut state = State::new();
+        state.mempool_config.max_size = max_size;
+        state.mempool = mempool;
+        state
+    }
+
+    #[test]
+    fn takes_higher_fees_first_and_equal_fees_in_arrival_order() {
+        let mempool = vec![tx(1, 1, 5), tx(2, 1, 10), tx(3, 1, 5)];
+
+        assert_eq!(selected(&mempool, 10), [(2, 1), (1, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn keeps_nonce_order_when_a_later_nonce_pays_more() {
+        let mempool = vec![tx(1, 2, 100), tx(1, 1, 1), tx(2, 1, 50)];
+
+        assert_eq!(selected(&mempool, 10), [(2, 1), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn batch_that_does_not_fit_blocks_later_nonces() {
+        let mut mempool = vec![tx(2, 1, 100)];
+        mempool.extend(batched(vec![tx(1, 1, 50), tx(1, 2, 50)]));
+        mempool.push(tx(1, 3, 1_000));
+        mempool.push(tx(3, 1, 1));
+
+        assert_eq!(selected(&mempool, 2), [(2, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn eviction_skips_batch_members() {
+        let mut state = state_with(batched(vec![tx(1, 1, 1), tx(1, 2, 1)]), 2);
+
+        assert_eq!(
+            admit(&mut state, vec![tx(2, 1, 5)]).err().unwrap(),
+            "network is congested, transactions can be processed in next block"
+        );
+        assert_eq!(state.mempool.len(), 2);
+    }
+
+    #[test]
+    fn eviction_skips_template_members() {
+        let in_template = tx(1, 1, 1);
+        let evicted = tx(2, 1, 2);
+        let mut state = state_with(vec![in_template.clone(), evicted.clone()], 2);
+        let template = Block::new(&Block::genesis(), vec![in_template.clone()], Hash::MAX, 0);
+        state.current_block = Some(template.unwrap());
+
+        admit(&mut state, vec![tx(3, 1, 5)]).unwrap();
+
+        assert_eq!(state.mempool, [in_template, tx(3, 1, 5)]);
+        assert_eq!(get_dropped(evicted.id()), Some(DropReason::Evicted));
+    }
+
+    #[test]
+    fn eviction_rejects_equal_fee_newcomers() {
+        let mut state = state_with(vec![tx(1, 1, 5), tx(2, 1, 5)], 2);
+
+        assert!(admit(&mut state, vec![tx(3, 1, 5)]).is_err());
+        assert_eq!(state.mempool, [tx(1, 1, 5), tx(2, 1, 5)]);
+    }
+}
@@ -0,0 +1,118 @@
+//! EXE payments by ledger block index. A payment is reserved before the first
+//! await that processes it, then committed once it paid for something or
+//! released so it can be used again. No call outlives an upgrade, so
+//! reservations are only held in the state; committed payments are appended
+//! to the payment log, which `restore` replays after an upgrade.
+
+use crate::memory::{ get_all_transactions, insert_new_transaction };
+use crate::{ mutate_state, State };
+
+/// Reservation of a payment. Dropping it without `commit` releases the
+/// payment, on every exit path of the call that holds it, traps included.
+#[must_use]
+pub struct PaymentReservation {
+    block_index: u64,
+    committed: bool,
+}
+
+impl PaymentReservation {
+    pub fn block_index(&self) -> u64 {
+        self.block_index
+    }
+
+    /// Marks the payment processed for good.
+    pub fn commit(mut self) {
+        self.committed = true;
+        mutate_state(|s| {
+            s.reserved_payments.remove(&self.block_index);
+            s.miner_creation_transactions.insert(self.block_index);
+        });
+        let _ = insert_new_transaction(self.block_index);
+    }
+}
+
+impl Drop for PaymentReservation {
+    fn drop(&mut self) {
+        if !self.committed {
+            mutate_state(|s| s.reserved_payments.remove(&self.block_index));
+        }
+    }
+}
+
+/// Reserves the payment `block_index`, unless it is processed or another
+/// call holds it.
+pub fn reserve(block_index: u64) -> Result<PaymentReservation, String> {
+    mutate_state(|s| {
+        if s.miner_creation_transactions.contains(&block_index) {
+            return Err("transaction already processed".to_string());
+        }
+        if !s.reserved_payments.insert(block_index) {
+            return Err("transaction is being processed".to_string());
+        }
+        Ok(())
+    })?;
+
+    Ok(PaymentReservation {
+        block_index,
+        committed: false,
+    })
+}
+
+/// Restores the committed payments from the payment log.
+pub fn restore(state: &mut State) {
+    state.miner_creation_transactions.extend(get_all_transactions());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ read_state, replace_state };
+
+    fn reserved(block_index: u64) -> bool {
+        read_state(|s| s.reserved_payments.contains(&block_index))
+    }
+
+    #[test]
+    fn second_reserve_of_a_held_payment_fails() {
+        replace_state(State::new());
+
+        let _reservation = reserve(7).unwrap();
+        assert_eq!(reserve(7).err().unwrap(), "transaction is being processed");
+        assert!(reserve(8).is_ok());
+    }
+
+    #[test]
+    fn dropping_a_reservation_releases_it() {
+        replace_state(State::new());
+
+        drop(reserve(7).unwrap());
+        assert!(!reserved(7));
+        assert!(reserve(7).is_ok());
+        assert!(get_all_transactions().is_empty());
+    }
+
+    #[test]
+    fn commit_blocks_later_reserves() {
+        replace_state(State::new());
+
+        reserve(7).unwrap().commit();
+        assert!(!reserved(7));
+        assert_eq!(reserve(7).err().unwrap(), "transaction already processed");
+        assert_eq!(get_all_transactions(), vec![7]);
+    }
+
+    #[test]
+    fn restore_replays_the_log() {
+        // entries written before payments were reserved
+        let _ = insert_new_transaction(3);
+        let _ = insert_new_transaction(5);
+        replace_state(State::new());
+        reserve(7).unwrap().commit();
+        let _held = reserve(9).unwrap();
+
+        let mut state = State::new();
+        restore(&mut state);
+        assert_eq!(state.miner_creation_transactions.into_iter().collect::<Vec<_>>(), [3, 5, 7]);
+        assert!(state.reserved_payments.is_empty());
+    }
+}
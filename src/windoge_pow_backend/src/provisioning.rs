@@ -0,0 +1,304 @@
+//! Miner provisioning. A verified EXE payment is recorded before any call is
+//! made, then walked through creating the canister and installing the miner.
+//! Failed steps are retried, on the same canister once it exists, and after
+//! `MAX_PROVISIONING_ATTEMPTS` the payment is refunded instead. Refunds send
+//! the same `created_at_time` and the payment index as memo on every attempt,
+//! so the ledger deduplicates them.
+
+use candid::{ CandidType, Encode, Principal };
+use num_traits::ToPrimitive;
+use serde::{ Deserialize, Serialize };
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use crate::ledger::{ burn, icrc1_transfer, BurnArgs, TransferArg, TransferError };
+use crate::memory::{
+    add_burned_exe,
+    get_provisioning,
+    insert_new_miner,
+    running_provisionings,
+    update_provisioning,
+    Account,
+};
+use crate::payments::PaymentReservation;
+use crate::queue::{ InFlight, Requeue, RetryQueue };
+use crate::miner::{ create_canister, install_code, reinstall_code };
+use crate::{
+    miner_wasm,
+    mutate_state,
+    WINDOGE_LEDGER_ID,
+    WINDOGE_MINER_CREATION_AMOUNT,
+};
+
+/// Failed attempts at creating or installing a miner before the payment is
+/// refunded.
+pub const MAX_PROVISIONING_ATTEMPTS: u32 = 5;
+/// Cycles the miner canister is created with.
+const MINER_CREATION_CYCLES: u64 = 2_500_000_000_000;
+/// Share of the creation amount burned once the miner is running.
+const MINER_CREATION_BURN_PERCENT: u64 = 40;
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ProvisioningStatus {
+    Creating,
+    /// The canister exists, the miner code isn't installed yet.
+    Installing,
+    Completed,
+    /// Provisioning gave up after `error`, the payment is being returned.
+    Refunding {
+        error: String,
+    },
+    Refunded {
+        /// Index of the refund on the EXE ledger.
+        refund_index: u64,
+        error: String,
+    },
+    /// The ledger rejected the refund for a reason retrying doesn't fix. A
+    /// controller can queue it again with `retry`.
+    RefundFailed {
+        error: String,
+    },
+}
+
+impl ProvisioningStatus {
+    /// Whether the provisioning still has steps to run.
+    pub fn is_running(&self) -> bool {
+        matches!(self, Self::Creating | Self::Installing | Self::Refunding { .. })
+    }
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Provisioning {
+    /// Index of the EXE payment on the ledger.
+    pub block_index: u64,
+    pub owner: Principal,
+    /// Account the payment came from, and a refund goes back to.
+    pub payer: Account,
+    pub amount: u64,
+    /// The miner canister, once created. It stays recorded after a refund so
+    /// the canister can be reclaimed.
+    pub canister_id: Option<Principal>,
+    /// Failed attempts at the current step.
+    pub attempts: u32,
+    pub refund_created_at_time: u64,
+    pub status: ProvisioningStatus,
+}
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<u64>> = RefCell::default();
+}
+
+/// Records the verified payment held by `reservation` and commits it, so it
+/// can't pay for a second miner. Returns the index to process.
+pub fn start(
+    reservation: PaymentReservation,
+    owner: Principal,
+    payer: Account,
+    amount: u64
+) -> u64 {
+    let block_index = reservation.block_index();
+    update_provisioning(Provisioning {
+        block_index,
+        owner,
+        payer,
+        amount,
+        canister_id: None,
+        attempts: 0,
+        refund_created_at_time: 0,
+        status: ProvisioningStatus::Creating,
+    });
+    reservation.commit();
+    block_index
+}
+
+/// Runs the steps of the provisioning `block_index` until it is done or a
+/// step has to be retried later.
+pub async fn process(block_index: u64) {
+    let Some(_in_flight) = InFlight::acquire(&IN_FLIGHT, block_index) else {
+        return;
+    };
+
+    while let Some(provisioning) = get_provisioning(block_index) {
+        if !provisioning.status.is_running() {
+            break;
+        }
+        let progressed = step(provisioning).await;
+        if !progressed {
+            break;
+        }
+    }
+}
+
+/// Runs the next step of `provisioning`. Returns whether it moved on to
+/// another step.
+async fn step(mut provisioning: Provisioning) -> bool {
+    match provisioning.status.clone() {
+        ProvisioningStatus::Creating => {
+            match create_canister(MINER_CREATION_CYCLES).await {
+                Ok(canister_id) => {
+                    provisioning.canister_id = Some(canister_id);
+                    provisioning.attempts = 0;
+                    provisioning.status = ProvisioningStatus::Installing;
+                }
+                Err(e) => {
+                    return fail_attempt(provisioning, format!("{} - {:?}", e.method, e.reason));
+                }
+            }
+        }
+        ProvisioningStatus::Installing => {
+            let canister_id = provisioning.canister_id.expect("installing without a canister");
+            let arg = Encode!(&provisioning.owner).unwrap();
+            // an earlier attempt may have left code behind
+            let result = if provisioning.attempts == 0 {
+                install_code(canister_id, miner_wasm().to_vec(), arg).await
+            } else {
+                reinstall_code(canister_id, miner_wasm().to_vec(), arg).await
+            };
+            if let Err(e) = result {
+                return fail_attempt(provisioning, format!("{} - {:?}", e.method, e.reason));
+            }
+
+            mutate_state(|s| {
+                s.new_miner(canister_id, provisioning.owner, provisioning.block_index);
+            });
+            insert_new_miner(canister_id, provisioning.owner, provisioning.block_index);
+            provisioning.attempts = 0;
+            provisioning.status = ProvisioningStatus::Completed;
+            update_provisioning(provisioning);
+            ic_cdk::println!("Miner {} spawned", canister_id.to_text());
+
+            burn_exe((WINDOGE_MINER_CREATION_AMOUNT * MINER_CREATION_BURN_PERCENT) / 100).await;
+            return true;
+        }
+        ProvisioningStatus::Refunding { error } => {
+            return refund(provisioning, error).await;
+        }
+        _ => {
+            return false;
+        }
+    }
+
+    update_provisioning(provisioning);
+    true
+}
+
+/// Counts a failed attempt at the current step, and moves on to the refund
+/// once they run out. Returns whether it did.
+fn fail_attempt(mut provisioning: Provisioning, error: String) -> bool {
+    ic_cdk::println!("Provisioning {} failed: {}", provisioning.block_index, error);
+
+    provisioning.attempts += 1;
+    let gave_up = provisioning.attempts >= MAX_PROVISIONING_ATTEMPTS;
+    if gave_up {
+        provisioning.attempts = 0;
+        provisioning.refund_created_at_time = ic_cdk::api::time();
+        provisioning.status = ProvisioningStatus::Refunding { error };
+    }
+    update_provisioning(provisioning);
+    gave_up
+}
+
+async fn refund(mut provisioning: Provisioning, error: String) -> bool {
+    let transfer = TransferArg {
+        to: provisioning.payer.clone(),
+        fee: None,
+        memo: Some(ByteBuf::from(provisioning.block_index.to_le_bytes().to_vec())),
+        from_subaccount: None,
+        created_at_time: Some(provisioning.refund_created_at_time),
+        amount: provisioning.amount.into(),
+    };
+    let ledger = Principal::from_text(WINDOGE_LEDGER_ID).unwrap();
+    let result = icrc1_transfer(transfer, ledger).await;
+
+    let progressed = match result {
+        Ok(Ok(refund_index)) | Ok(Err(TransferError::Duplicate { duplicate_of: refund_index })) => {
+            ic_cdk::println!("Refunded payment {}", provisioning.block_index);
+            provisioning.status = ProvisioningStatus::Refunded {
+                refund_index: refund_index.0.to_u64().unwrap_or(u64::MAX),
+                error,
+            };
+            true
+        }
+        Ok(Err(TransferError::TemporarilyUnavailable)) => {
+            ic_cdk::println!(
+                "EXE ledger unavailable, refund {} stays queued",
+                provisioning.block_index
+            );
+            provisioning.attempts += 1;
+            false
+        }
+        Err(e) => {
+            ic_cdk::println!("Error refunding payment {}: {}", provisioning.block_index, e);
+            provisioning.attempts += 1;
+            false
+        }
+        Ok(Err(e)) => {
+            ic_cdk::println!("Error refunding payment {}: {:?}", provisioning.block_index, e);
+            provisioning.status = ProvisioningStatus::RefundFailed {
+                error: format!("{}, refund: {:?}", error, e),
+            };
+            true
+        }
+    };
+    update_provisioning(provisioning);
+    progressed
+}
+
+/// Burns `amount` EXE of the backend's own balance.
+pub async fn burn_exe(amount: u64) {
+    let args = BurnArgs {
+        memo: None,
+        from_subaccount: None,
+        created_at_time: None,
+        amount: amount.into(),
+    };
+    match burn(args, Principal::from_text(WINDOGE_LEDGER_ID).unwrap()).await {
+        Ok(Ok(index)) => {
+            ic_cdk::println!("Burned {} EXE, index: {:?}", amount, index);
+            mutate_state(|s| {
+                s.exe_burned += amount;
+            });
+            let _ = add_burned_exe(amount);
+        }
+        Ok(Err(e)) => ic_cdk::println!("Error burning {} EXE: {:?}", amount, e),
+        Err(e) => ic_cdk::println!("Error burning {} EXE: {}", amount, e),
+    }
+}
+
+/// Moves the provisioning `block_index` from `RefundFailed` back to
+/// `Refunding`. The refund is sent with a fresh `created_at_time`, since the
+/// one it failed with may be too old for the ledger by now.
+pub fn retry(block_index: u64, now: u64) -> Result<(), String> {
+    let mut provisioning = get_provisioning(block_index).ok_or(
+        "provisioning not found".to_string()
+    )?;
+    let ProvisioningStatus::RefundFailed { error } = provisioning.status.clone() else {
+        return Err("refund has not failed".to_string());
+    };
+
+    provisioning.status = ProvisioningStatus::Refunding { error };
+    provisioning.refund_created_at_time = now;
+    update_provisioning(provisioning);
+    Ok(())
+}
+
+/// Queue of the provisionings still creating, installing or refunding.
+pub struct Provisionings;
+
+impl RetryQueue for Provisionings {
+    type Key = u64;
+
+    fn queued() -> Vec<u64> {
+        running_provisionings()
+    }
+
+    fn process(block_index: u64) -> impl std::future::Future<Output = ()> + 'static {
+        process(block_index)
+    }
+}
+
+impl Requeue for Provisionings {
+    fn retry(block_index: u64, now: u64) -> Result<(), String> {
+        retry(block_index, now)
+    }
+}
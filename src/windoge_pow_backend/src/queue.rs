@@ -0,0 +1,55 @@
+//! Work kept in stable memory until a ledger or management canister call
+//! settles it. A queue is walked on a timer to retry what is still queued,
+//! and a controller can queue an entry the ledger rejected again. Entries are
+//! marked in flight while a call processes them, so two calls never process
+//! the same entry at once.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::thread::LocalKey;
+
+/// Seconds between two passes over the queues.
+pub const RETRY_INTERVAL_SECS: u64 = 30;
+
+pub trait RetryQueue {
+    type Key: Ord + Clone + 'static;
+
+    /// Entries with steps left to run.
+    fn queued() -> Vec<Self::Key>;
+
+    /// Runs the entry `key` as far as it gets.
+    fn process(key: Self::Key) -> impl Future<Output = ()> + 'static;
+}
+
+/// Queue whose rejected entries a controller can queue again.
+pub trait Requeue: RetryQueue {
+    /// Queues the entry `key` the ledger rejected again.
+    fn retry(key: Self::Key, now: u64) -> Result<(), String>;
+}
+
+/// Processes every queued entry of `Q`.
+pub fn process_queue<Q: RetryQueue>() {
+    for key in Q::queued() {
+        ic_cdk::spawn(Q::process(key));
+    }
+}
+
+/// Marks `key` in flight in `set` until it is dropped.
+pub struct InFlight<K: Ord + Clone + 'static> {
+    set: &'static LocalKey<RefCell<BTreeSet<K>>>,
+    key: K,
+}
+
+impl<K: Ord + Clone + 'static> InFlight<K> {
+    /// `None` while another call holds `key`.
+    pub fn acquire(set: &'static LocalKey<RefCell<BTreeSet<K>>>, key: K) -> Option<Self> {
+        set.with(|s| s.borrow_mut().insert(key.clone())).then_some(Self { set, key })
+    }
+}
+
+impl<K: Ord + Clone + 'static> Drop for InFlight<K> {
+    fn drop(&mut self) {
+        self.set.with(|s| s.borrow_mut().remove(&self.key));
+    }
+}
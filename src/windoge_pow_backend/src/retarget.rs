@@ -0,0 +1,168 @@
+use candid::CandidType;
+use serde::{ Deserialize, Serialize };
+use windoge_protocol::compact::normalize_target;
+use crate::memory::Hash;
+use crate::SEC_NANOS;
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct RetargetConfig {
+    /// Number of most recent blocks whose block times are averaged.
+    pub window: u64,
+    /// Block time the target is steered towards, in seconds.
+    pub block_time_secs: u64,
+    /// Largest change of the expected work per block, in percent.
+    pub max_step_percent: u64,
+    pub min_difficulty: u32,
+    pub max_difficulty: u32,
+}
+
+impl Default for RetargetConfig {
+    fn default() -> Self {
+        Self {
+            window: 12,
+            block_time_secs: 300,
+            max_step_percent: 25,
+            min_difficulty: 24,
+            max_difficulty: 48,
+        }
+    }
+}
+
+impl RetargetConfig {
+    /// Rejects configurations `next_target` can't work with.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window == 0 {
+            return Err("window must be positive".to_string());
+        }
+        if self.block_time_secs == 0 {
+            return Err("block_time_secs must be positive".to_string());
+        }
+        if self.block_time_secs.checked_mul(SEC_NANOS).is_none() {
+            return Err("block_time_secs overflows in nanoseconds".to_string());
+        }
+        if self.min_difficulty > self.max_difficulty || self.max_difficulty > 128 {
+            return Err("difficulty bounds must satisfy min <= max <= 128".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Target for a fractional difficulty, where difficulty `d` expects the same
+/// work as `d` leading zero bits: a hash is valid when `hash <= target`.
+pub fn difficulty_to_target(difficulty: f64) -> Hash {
+    let bound = (128.0 - difficulty).exp2();
+    if bound >= (128_f64).exp2() {
+        return Hash::MAX;
+    }
+    (bound as Hash).saturating_sub(1)
+}
+
+pub fn target_to_difficulty(target: Hash) -> f64 {
+    128.0 - ((target as f64) + 1.0).log2()
+}
+
+/// Integer difficulty written into block headers for `target`, only
+/// binding for blocks older than version 3.
+pub fn header_difficulty(target: Hash) -> u32 {
+    target.leading_zeros()
+}
+
+/// Target for the next block from the block times (in nanoseconds) of the
+/// most recent blocks, measured from template publication to acceptance.
+/// The log2 error of the window average against the configured block time
+/// is spread over the window, so a sustained error is corrected over roughly
+/// `window` blocks instead of being applied on every block it stays in the
+/// average.
+pub fn next_target(current_target: Hash, solve_times: &[u64], config: &RetargetConfig) -> Hash {
+    if solve_times.is_empty() || config.window == 0 {
+        return current_target;
+    }
+
+    let total: u128 = solve_times
+        .iter()
+        .map(|t| *t as u128)
+        .sum();
+    let average = ((total / (solve_times.len() as u128)) as f64).max(1.0);
+    let expected = config.block_time_secs.saturating_mul(SEC_NANOS) as f64;
+
+    let max_step = (1.0 + (config.max_step_percent as f64) / 100.0).log2();
+    let step = (-(average / expected).log2() / (config.window as f64)).clamp(-max_step, max_step);
+
+    let difficulty = (target_to_difficulty(current_target) + step).clamp(
+        config.min_difficulty as f64,
+        config.max_difficulty as f64
+    );
+
+    normalize_target(difficulty_to_target(difficulty))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPECTED: u64 = 300 * SEC_NANOS;
+
+    fn retarget(difficulty: f64, solve_time: u64) -> f64 {
+        let config = RetargetConfig::default();
+        let solve_times = vec![solve_time; config.window as usize];
+        target_to_difficulty(next_target(difficulty_to_target(difficulty), &solve_times, &config))
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-3, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn keeps_the_target_on_time() {
+        assert_close(retarget(30.0, EXPECTED), 30.0);
+    }
+
+    #[test]
+    fn raises_the_difficulty_for_fast_blocks() {
+        // half the block time is one bit of error, spread over the window
+        assert_close(retarget(30.0, EXPECTED / 2), 30.0 + 1.0 / 12.0);
+    }
+
+    #[test]
+    fn lowers_the_difficulty_for_slow_blocks() {
+        assert_close(retarget(30.0, EXPECTED * 2), 30.0 - 1.0 / 12.0);
+    }
+
+    #[test]
+    fn clamps_the_step() {
+        let max_step = (1.25_f64).log2();
+        assert_close(retarget(30.0, 1), 30.0 + max_step);
+        assert_close(retarget(30.0, EXPECTED * 1_000_000), 30.0 - max_step);
+    }
+
+    #[test]
+    fn clamps_the_difficulty_bounds() {
+        assert_close(retarget(48.0, 1), 48.0);
+        assert_close(retarget(24.0, EXPECTED * 1_000_000), 24.0);
+    }
+
+    #[test]
+    fn keeps_the_target_without_block_times() {
+        let target = difficulty_to_target(30.0);
+        assert_eq!(next_target(target, &[], &RetargetConfig::default()), target);
+    }
+
+    #[test]
+    fn difficulty_round_trips() {
+        for difficulty in [0.0, 1.0, 24.0, 24.5, 33.25, 48.0, 100.0, 127.0] {
+            let round_trip = target_to_difficulty(difficulty_to_target(difficulty));
+            assert!((round_trip - difficulty).abs() < 1e-9, "{}", difficulty);
+        }
+        assert_eq!(difficulty_to_target(0.0), Hash::MAX);
+    }
+
+    #[test]
+    fn rejects_a_block_time_overflowing_nanoseconds() {
+        let longest = u64::MAX / SEC_NANOS;
+        let config = RetargetConfig { block_time_secs: longest + 1, ..Default::default() };
+        assert!(config.validate().is_err());
+
+        let config = RetargetConfig { block_time_secs: longest, ..Default::default() };
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,94 @@
+//! Emission curve derived from `COINBASE_REWARDS` and `BLOCK_HALVING`, and
+//! the per-block totals seeding the supply totals kept in stable memory.
+
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use crate::memory::{ block_count, get_accepted_at, get_block, Block };
+use crate::{ rewards_at, BLOCK_HALVING };
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Supply {
+    /// Block rewards credited so far, without fees.
+    pub minted: u64,
+    /// Balances withdrawn to the BIL ledger.
+    pub withdrawn: u64,
+    /// BIL deposited from the ledger onto the chain.
+    pub deposited: u64,
+    /// Balances held on chain, `minted + deposited - withdrawn`.
+    pub circulating: u64,
+    /// Rewards of all blocks until the reward reaches zero.
+    pub max_supply: u64,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Halving {
+    /// First block paying `reward`.
+    pub height: u64,
+    pub reward: u64,
+    /// Acceptance time of the block at `height` once it is mined, otherwise
+    /// an estimate from the average block time. `None` while no average is
+    /// known yet.
+    pub timestamp: Option<u64>,
+}
+
+/// Number of halvings until the reward reaches zero.
+fn halving_count() -> u64 {
+    let mut epoch = 0;
+    while rewards_at(epoch * BLOCK_HALVING) > 0 {
+        epoch += 1;
+    }
+    epoch
+}
+
+pub fn max_supply() -> u64 {
+    (0..halving_count())
+        .map(|epoch| {
+            // genesis pays no reward
+            let blocks = if epoch == 0 { BLOCK_HALVING - 1 } else { BLOCK_HALVING };
+            rewards_at(epoch * BLOCK_HALVING) * blocks
+        })
+        .sum()
+}
+
+/// Every halving with the reward it starts, the last one bringing the reward
+/// to zero. `average_block_time` is in nanoseconds.
+pub fn halving_schedule(now: u64, average_block_time: u64) -> Vec<Halving> {
+    let tip = block_count().saturating_sub(1);
+
+    (1..=halving_count())
+        .map(|epoch| {
+            let height = epoch * BLOCK_HALVING;
+            let timestamp = if height <= tip {
+                get_accepted_at(height).or_else(|| get_block(height).map(|b| b.header.timestamp))
+            } else if average_block_time > 0 {
+                Some(now.saturating_add((height - tip).saturating_mul(average_block_time)))
+            } else {
+                None
+            };
+
+            Halving {
+                height,
+                reward: rewards_at(height),
+                timestamp,
+            }
+        })
+        .collect()
+}
+
+/// Reward minted and amount withdrawn by a stored block, used to seed the
+/// supply totals of canisters upgraded from before they were tracked.
+/// Withdrawals whose ledger mint failed are counted as withdrawn.
+pub fn block_totals(block: &Block, backend: Principal) -> (u64, u64) {
+    // before the coinbase, the reward was read after appending the block
+    let minted = match block.coinbase() {
+        Some(coinbase) => coinbase.amount.saturating_sub(block.fees()),
+        None => rewards_at(block.header.height + 1),
+    };
+    let withdrawn = block.transactions
+        .iter()
+        .filter(|tx| !tx.is_coinbase() && tx.recipient == backend)
+        .map(|tx| tx.amount)
+        .sum::<u64>();
+
+    (minted, withdrawn)
+}
@@ -0,0 +1,261 @@
+use serde::{ Deserialize, Serialize };
+use std::collections::{ BTreeMap, BTreeSet };
+use std::fmt;
+use candid::{ CandidType, Principal };
+use crate::memory::{ Block, Hash, Transaction };
+use windoge_protocol::batch_id;
+
+#[derive(Clone, Copy, CandidType, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TemplateField {
+    Version,
+    Height,
+    PrevHash,
+    Timestamp,
+    Difficulty,
+    Target,
+    Transactions,
+}
+
+impl fmt::Display for TemplateField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TemplateField::Version => "version",
+            TemplateField::Height => "height",
+            TemplateField::PrevHash => "prev_hash",
+            TemplateField::Timestamp => "timestamp",
+            TemplateField::Difficulty => "difficulty",
+            TemplateField::Target => "target",
+            TemplateField::Transactions => "transactions",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, CandidType, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SolutionError {
+    AnonymousCaller,
+    UnregisteredMiner,
+    NoTemplate,
+    OutdatedChainState,
+    HeightMismatch,
+    TemplateMismatch(TemplateField),
+    InvalidMerkleRoot,
+    MissingCoinbase,
+    InvalidCoinbase(String),
+    DuplicateTransaction,
+    InvalidNonce,
+    IncompleteBatch,
+    InvalidHash,
+    InvalidSolution,
+}
+
+impl fmt::Display for SolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolutionError::AnonymousCaller => write!(f, "caller is anonymous"),
+            SolutionError::UnregisteredMiner => write!(f, "Unregistered miner"),
+            SolutionError::NoTemplate => write!(f, "No block template published"),
+            SolutionError::OutdatedChainState =>
+                write!(f, "Block references outdated chain state"),
+            SolutionError::HeightMismatch => write!(f, "Block height mismatch"),
+            SolutionError::TemplateMismatch(field) =>
+                write!(f, "Block does not match template: {}", field),
+            SolutionError::InvalidMerkleRoot => write!(f, "Invalid merkle root"),
+            SolutionError::MissingCoinbase => write!(f, "Block has no coinbase"),
+            SolutionError::InvalidCoinbase(reason) => write!(f, "Invalid coinbase: {}", reason),
+            SolutionError::DuplicateTransaction => write!(f, "Duplicate transaction"),
+            SolutionError::InvalidNonce => write!(f, "Transaction nonce out of order"),
+            SolutionError::IncompleteBatch => write!(f, "Incomplete transaction batch"),
+            SolutionError::InvalidHash => write!(f, "Block hash does not match header"),
+            SolutionError::InvalidSolution => write!(f, "Invalid solution"),
+        }
+    }
+}
+
+/// Checks that a submitted block is the template published by `create_block`,
+/// with only the coinbase recipient, nonce and hash filled in by the miner.
+pub fn check_template(block: &Block, template: &Block) -> Result<(), SolutionError> {
+    let (header, expected) = (&block.header, &template.header);
+
+    if header.version != expected.version {
+        return Err(SolutionError::TemplateMismatch(TemplateField::Version));
+    }
+    if header.height != expected.height {
+        return Err(SolutionError::TemplateMismatch(TemplateField::Height));
+    }
+    if header.prev_hash != expected.prev_hash {
+        return Err(SolutionError::TemplateMismatch(TemplateField::PrevHash));
+    }
+    if header.timestamp != expected.timestamp {
+        return Err(SolutionError::TemplateMismatch(TemplateField::Timestamp));
+    }
+    if header.difficulty != expected.difficulty {
+        return Err(SolutionError::TemplateMismatch(TemplateField::Difficulty));
+    }
+    if header.target != expected.target {
+        return Err(SolutionError::TemplateMismatch(TemplateField::Target));
+    }
+    if !same_transactions(block, template) {
+        return Err(SolutionError::TemplateMismatch(TemplateField::Transactions));
+    }
+    if Block::calculate_merkle_root(&block.transactions, header.version) != header.merkle_root {
+        return Err(SolutionError::InvalidMerkleRoot);
+    }
+
+    Ok(())
+}
+
+fn same_transactions(block: &Block, template: &Block) -> bool {
+    if block.transactions.len() != template.transactions.len() {
+        return false;
+    }
+
+    block.transactions
+        .iter()
+        .zip(template.transactions.iter())
+        .all(|(tx, expected)| {
+            if expected.is_coinbase() {
+                // the miner only picks the account the reward is paid to
+                let claimed = Transaction {
+                    recipient: tx.recipient,
+                    to_subaccount: tx.to_subaccount.clone(),
+                    ..expected.clone()
+                };
+                *tx == claimed
+            } else {
+                tx == expected
+            }
+        })
+}
+
+/// Checks that the block mints exactly `reward` to `recipient` through a single
+/// coinbase at the start of the block.
+pub fn check_coinbase(
+    block: &Block,
+    recipient: Principal,
+    reward: u64
+) -> Result<(), SolutionError> {
+    let coinbase = block.coinbase().ok_or(SolutionError::MissingCoinbase)?;
+
+    if coinbase.recipient != recipient {
+        return Err(SolutionError::InvalidCoinbase("recipient is not the miner owner".to_string()));
+    }
+    if coinbase.amount != reward {
+        return Err(
+            SolutionError::InvalidCoinbase(
+                format!("amount {} does not match reward {}", coinbase.amount, reward)
+            )
+        );
+    }
+    if block.transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+        return Err(SolutionError::InvalidCoinbase("more than one coinbase".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Checks that every transaction is included once and that each sender's
+/// nonces strictly increase, starting above `committed`, the latest nonce of
+/// the sender already on chain.
+pub fn check_nonces(
+    block: &Block,
+    committed: impl Fn(Principal) -> u64
+) -> Result<(), SolutionError> {
+    let mut ids = BTreeSet::new();
+    let mut latest: BTreeMap<Principal, u64> = BTreeMap::new();
+
+    for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+        if !ids.insert(tx.id()) {
+            return Err(SolutionError::DuplicateTransaction);
+        }
+
+        let previous = *latest.entry(tx.sender).or_insert_with(|| committed(tx.sender));
+        if tx.nonce <= previous {
+            return Err(SolutionError::InvalidNonce);
+        }
+        latest.insert(tx.sender, tx.nonce);
+    }
+
+    Ok(())
+}
+
+/// Checks that every batch in the block is complete: all of its transactions
+/// are included, from one sender, and hash to the batch id.
+pub fn check_batches(block: &Block) -> Result<(), SolutionError> {
+    let mut batches: BTreeMap<Hash, (u32, Vec<Transaction>)> = BTreeMap::new();
+    for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+        if let Some(batch) = tx.batch {
+            batches.entry(batch.id).or_insert((batch.size, Vec::new())).1.push(tx.clone());
+        }
+    }
+
+    for (id, (size, mut members)) in batches {
+        members.sort_by_key(|tx| tx.nonce);
+        let one_sender = members.iter().all(|tx| tx.sender == members[0].sender);
+        if members.len() != (size as usize) || !one_sender || batch_id(&members) != id {
+            return Err(SolutionError::IncompleteBatch);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bytes::ByteBuf;
+    use windoge_protocol::testing::{ mine, owner, template };
+    use windoge_protocol::BatchRef;
+
+    #[test]
+    fn accepts_a_mined_template() {
+        let template = template();
+        let block = mine(&template, owner());
+
+        assert_eq!(check_template(&block, &template), Ok(()));
+        assert_eq!(check_coinbase(&block, owner(), 600), Ok(()));
+        assert_eq!(block.calculate_hash(), block.hash);
+        assert!(block.meets_target(block.hash));
+    }
+
+    #[test]
+    fn rejects_a_recipient_outside_the_merkle_root() {
+        let template = template();
+        let mut block = mine(&template, owner());
+        block.transactions[0].recipient = Principal::anonymous();
+
+        assert_eq!(check_template(&block, &template), Err(SolutionError::InvalidMerkleRoot));
+    }
+
+    #[test]
+    fn accepts_a_coinbase_to_a_subaccount() {
+        let template = template();
+        let mut claimed = template.clone();
+        claimed.transactions[0].to_subaccount = Some(ByteBuf::from(vec![1; 32]));
+        let block = mine(&claimed, owner());
+
+        assert_eq!(check_template(&block, &template), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_coinbase_with_other_changes() {
+        let template = template();
+        let changes: [fn(&mut Transaction); 4] = [
+            |tx| tx.nonce = 1,
+            |tx| tx.fee = 1,
+            |tx| tx.memo = Some(ByteBuf::from(b"spam".to_vec())),
+            |tx| tx.batch = Some(BatchRef { id: 42, size: 1 }),
+        ];
+
+        for change in changes {
+            let mut claimed = template.clone();
+            change(&mut claimed.transactions[0]);
+            let block = mine(&claimed, owner());
+
+            assert_eq!(
+                check_template(&block, &template),
+                Err(SolutionError::TemplateMismatch(TemplateField::Transactions))
+            );
+        }
+    }
+}
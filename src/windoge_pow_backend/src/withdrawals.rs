@@ -0,0 +1,163 @@
+//! Withdrawals of on-chain balances to the BIL ledger. A mined transfer to
+//! the backend debits the sender right away and queues a withdrawal, which is
+//! retried until the ledger settles it. Every attempt sends the same
+//! `created_at_time` and the transaction id as memo, so the ledger
+//! deduplicates attempts that went through without the backend noticing.
+
+use candid::{ CandidType, Principal };
+use num_traits::ToPrimitive;
+use serde::{ Deserialize, Serialize };
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use crate::ledger::{ icrc1_transfer, TransferArg, TransferError };
+use crate::memory::{
+    add_withdrawn,
+    get_withdrawal,
+    queued_withdrawals,
+    sub_balance,
+    update_withdrawal,
+    Account,
+    Hash,
+    Transaction,
+};
+use crate::queue::{ InFlight, Requeue, RetryQueue };
+use crate::BIL_LEDGER_ID;
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum WithdrawalStatus {
+    Queued,
+    Completed {
+        /// Index of the mint on the BIL ledger.
+        block_index: u64,
+    },
+    /// Rejected by the ledger for a reason retrying doesn't fix. A controller
+    /// can queue it again with `retry`.
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Withdrawal {
+    /// Id of the mined transaction that requested the withdrawal.
+    pub tx_id: Hash,
+    /// Ledger account the BIL is minted to, the account the transaction
+    /// debited.
+    pub to: Account,
+    pub amount: u64,
+    pub created_at_time: u64,
+    pub attempts: u32,
+    pub status: WithdrawalStatus,
+}
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<Hash>> = RefCell::default();
+}
+
+/// Debits the withdrawn amount of the mined `transaction` and queues its
+/// withdrawal. Returns the id to process. A withdrawal already recorded
+/// under the same id is never replaced, and nothing is debited then.
+pub fn enqueue(transaction: &Transaction, now: u64) -> Result<Hash, String> {
+    if get_withdrawal(transaction.id()).is_some() {
+        return Err(format!("withdrawal {} already exists", transaction.id()));
+    }
+
+    let withdrawal = Withdrawal {
+        tx_id: transaction.id(),
+        to: transaction.from(),
+        amount: transaction.amount,
+        created_at_time: now,
+        attempts: 0,
+        status: WithdrawalStatus::Queued,
+    };
+
+    sub_balance(&withdrawal.to, withdrawal.amount);
+    let _ = add_withdrawn(withdrawal.amount);
+    update_withdrawal(withdrawal.clone());
+    Ok(withdrawal.tx_id)
+}
+
+/// Mints the queued withdrawal `tx_id` on the BIL ledger.
+pub async fn process(tx_id: Hash) {
+    let Some(mut withdrawal) = get_withdrawal(tx_id) else {
+        return;
+    };
+    if withdrawal.status != WithdrawalStatus::Queued {
+        return;
+    }
+    let Some(_in_flight) = InFlight::acquire(&IN_FLIGHT, tx_id) else {
+        return;
+    };
+
+    let transfer = TransferArg {
+        to: withdrawal.to.clone(),
+        fee: None,
+        memo: Some(ByteBuf::from(tx_id.to_le_bytes().to_vec())),
+        from_subaccount: None,
+        created_at_time: Some(withdrawal.created_at_time),
+        amount: withdrawal.amount.into(),
+    };
+    let result = icrc1_transfer(transfer, Principal::from_text(BIL_LEDGER_ID).unwrap()).await;
+
+    withdrawal.attempts += 1;
+    match result {
+        Ok(Ok(block_index)) | Ok(Err(TransferError::Duplicate { duplicate_of: block_index })) => {
+            ic_cdk::println!("BIL minted successfully");
+            withdrawal.status = WithdrawalStatus::Completed {
+                block_index: block_index.0.to_u64().unwrap_or(u64::MAX),
+            };
+        }
+        Ok(Err(TransferError::TemporarilyUnavailable)) => {
+            ic_cdk::println!("BIL ledger unavailable, withdrawal {} stays queued", tx_id);
+        }
+        Err(e) => {
+            ic_cdk::println!("Error minting BIL, withdrawal {} stays queued: {}", tx_id, e);
+        }
+        Ok(Err(e)) => {
+            ic_cdk::println!("Error minting BIL: {:?}", e);
+            withdrawal.status = WithdrawalStatus::Failed {
+                error: format!("{:?}", e),
+            };
+        }
+    }
+    update_withdrawal(withdrawal);
+}
+
+/// Queues the failed withdrawal `tx_id` again. It gets a new
+/// `created_at_time`, as the ledger rejects old ones.
+pub fn retry(tx_id: Hash, now: u64) -> Result<(), String> {
+    let mut withdrawal = get_withdrawal(tx_id).ok_or("withdrawal not found".to_string())?;
+    if !matches!(withdrawal.status, WithdrawalStatus::Failed { .. }) {
+        return Err("withdrawal has not failed".to_string());
+    }
+
+    withdrawal.status = WithdrawalStatus::Queued;
+    withdrawal.created_at_time = now;
+    update_withdrawal(withdrawal);
+    Ok(())
+}
+
+/// Queue of the withdrawals waiting for their mint.
+pub struct Withdrawals;
+
+impl RetryQueue for Withdrawals {
+    type Key = Hash;
+
+    fn queued() -> Vec<Hash> {
+        queued_withdrawals()
+            .into_iter()
+            .map(|withdrawal| withdrawal.tx_id)
+            .collect()
+    }
+
+    fn process(tx_id: Hash) -> impl std::future::Future<Output = ()> + 'static {
+        process(tx_id)
+    }
+}
+
+impl Requeue for Withdrawals {
+    fn retry(tx_id: Hash, now: u64) -> Result<(), String> {
+        retry(tx_id, now)
+    }
+}
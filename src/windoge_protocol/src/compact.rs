@@ -0,0 +1,53 @@
+//! Compact "bits" encoding of 128-bit targets, as used by Bitcoin's `nBits`:
+//! the high byte is the size of the target in bytes and the low three bytes
+//! are its most significant bytes. Targets carried in block headers are
+//! always the expansion of some compact value.
+
+use crate::Hash;
+
+pub fn target_to_bits(target: Hash) -> u32 {
+    if target == 0 {
+        return 0;
+    }
+
+    let mut size = (128 - target.leading_zeros()).div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        (target << (8 * (3 - size))) as u32
+    } else {
+        (target >> (8 * (size - 3))) as u32
+    };
+
+    // The top mantissa bit is a sign bit in Bitcoin's encoding; keep it clear
+    // so the same bits decode identically in both formats.
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    (size << 24) | mantissa
+}
+
+/// Expands compact bits to a target, saturating at `Hash::MAX` for sizes
+/// beyond 128 bits.
+pub fn bits_to_target(bits: u32) -> Hash {
+    let size = bits >> 24;
+    let mantissa = (bits & 0x007f_ffff) as Hash;
+
+    if size <= 3 {
+        return mantissa >> (8 * (3 - size));
+    }
+
+    let shift = 8 * (size - 3);
+    if mantissa == 0 {
+        return 0;
+    }
+    if shift >= 128 || mantissa.leading_zeros() < shift {
+        return Hash::MAX;
+    }
+    mantissa << shift
+}
+
+/// Rounds a target down to the nearest value representable in compact form.
+pub fn normalize_target(target: Hash) -> Hash {
+    bits_to_target(target_to_bits(target))
+}
@@ -0,0 +1,505 @@
+use candid::{ CandidType, Principal };
+use rapidhash::RapidHasher;
+use serde::{ Deserialize, Serialize };
+use serde_bytes::ByteBuf;
+use sha2::{ Digest, Sha256 };
+use std::hash::Hasher;
+use compact::normalize_target;
+
+pub mod compact;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod vectors;
+
+pub type Hash = u128; // 128-bit hash
+
+/// Version written into new block headers. Version 1 blocks predate the
+/// canonical header encoding and are hashed with their original layout.
+/// Versions 1 and 2 count leading zero bits against `difficulty`; version 3
+/// compares the hash against the 128-bit `target`. Version 4 hashes the
+/// coinbase into the merkle root by its id, like transfers with a nonce.
+pub const BLOCK_VERSION: u32 = 4;
+
+/// Longest memo a transaction may carry, in bytes.
+pub const MAX_MEMO_LEN: usize = 32;
+
+/// Length of a subaccount, as in ICRC-1.
+pub const SUBACCOUNT_LEN: usize = 32;
+
+/// RapidHash of `data`, widened to 128 bits by rehashing the low half.
+pub fn hash128(data: &[u8]) -> Hash {
+    let mut hasher = RapidHasher::new(0);
+    hasher.write(data);
+    widen(hasher.finish())
+}
+
+/// SHA-256 of `data`, truncated to its first 128 bits. Used for ids that a
+/// user could otherwise craft to collide with another user's.
+pub fn sha256_128(data: &[u8]) -> Hash {
+    let digest = Sha256::digest(data);
+    Hash::from_le_bytes(digest[..16].try_into().unwrap())
+}
+
+fn widen(hash64: u64) -> Hash {
+    let hash128_high = {
+        let mut hasher = RapidHasher::new(hash64);
+        hasher.write(&hash64.to_le_bytes());
+        hasher.finish()
+    };
+
+    ((hash128_high as u128) << 64) | (hash64 as u128)
+}
+
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum TransactionKind {
+    #[default]
+    Transfer = 0,
+    /// Block reward minted to `recipient`. Only valid as the first
+    /// transaction of a block.
+    Coinbase = 1,
+}
+
+/// Marks a transaction as one of `size` transactions of the same sender that
+/// are included in a block together or not at all.
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BatchRef {
+    /// `batch_id` of the batch.
+    pub id: Hash,
+    pub size: u32,
+}
+
+/// ICRC-1 style account. The all-zero subaccount is the default one and is
+/// always stored as `None`, see `Account::new`.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<ByteBuf>,
+}
+
+impl Account {
+    pub fn new(owner: Principal, subaccount: Option<ByteBuf>) -> Self {
+        let subaccount = subaccount.filter(|s| s.iter().any(|b| *b != 0));
+        Self { owner, subaccount }
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self { owner, subaccount: None }
+    }
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Transaction {
+    pub sender: Principal,
+    pub recipient: Principal,
+    pub amount: u64,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub kind: TransactionKind,
+    /// Paid by the sender to the owner of the miner that mines the block,
+    /// on top of `amount`.
+    #[serde(default)]
+    pub fee: u64,
+    /// Position in the sender's sequence of transactions, strictly
+    /// increasing. Zero for coinbases and transfers created before nonces.
+    #[serde(default)]
+    pub nonce: u64,
+    #[serde(default)]
+    pub batch: Option<BatchRef>,
+    /// Reference chosen by the sender, at most `MAX_MEMO_LEN` bytes.
+    #[serde(default)]
+    pub memo: Option<ByteBuf>,
+    /// Subaccount of `sender` the amount and fee are taken from, `None` for
+    /// the default one.
+    #[serde(default)]
+    pub from_subaccount: Option<ByteBuf>,
+    /// Subaccount of `recipient` the amount is credited to.
+    #[serde(default)]
+    pub to_subaccount: Option<ByteBuf>,
+}
+
+impl Transaction {
+    /// Coinbase minting `amount` out of nowhere; the sender is the management
+    /// canister so it can never collide with a real account.
+    pub fn coinbase(recipient: Principal, amount: u64, timestamp: u64) -> Self {
+        Self {
+            sender: Principal::management_canister(),
+            recipient,
+            amount,
+            timestamp,
+            kind: TransactionKind::Coinbase,
+            fee: 0,
+            nonce: 0,
+            batch: None,
+            memo: None,
+            from_subaccount: None,
+            to_subaccount: None,
+        }
+    }
+
+    /// Account debited by the transaction.
+    pub fn from(&self) -> Account {
+        Account::new(self.sender, self.from_subaccount.clone())
+    }
+
+    /// Account credited by the transaction.
+    pub fn to(&self) -> Account {
+        Account::new(self.recipient, self.to_subaccount.clone())
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.kind == TransactionKind::Coinbase
+    }
+
+    /// Canonical serialization hashed into the transaction id. Principals are
+    /// length-prefixed, integers little-endian. Optional fields are only
+    /// written when present, each behind its own tag byte: 1 for the batch
+    /// reference, 2 for the length-prefixed memo, 3 and 4 for the length-prefixed
+    /// sender and recipient subaccounts.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(96);
+        data.push(self.kind as u8);
+        for principal in [self.sender, self.recipient] {
+            let bytes = principal.as_slice();
+            data.push(bytes.len() as u8);
+            data.extend_from_slice(bytes);
+        }
+        data.extend_from_slice(&self.amount.to_le_bytes());
+        data.extend_from_slice(&self.fee.to_le_bytes());
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+        if let Some(batch) = self.batch {
+            data.push(1);
+            data.extend_from_slice(&batch.id.to_le_bytes());
+            data.extend_from_slice(&batch.size.to_le_bytes());
+        }
+        if let Some(memo) = &self.memo {
+            data.push(2);
+            data.push(memo.len() as u8);
+            data.extend_from_slice(memo);
+        }
+        for (tag, subaccount) in [(3, &self.from_subaccount), (4, &self.to_subaccount)] {
+            if let Some(subaccount) = subaccount {
+                data.push(tag);
+                data.push(subaccount.len() as u8);
+                data.extend_from_slice(subaccount);
+            }
+        }
+        data
+    }
+
+    /// Unique id of the transaction, covering every field.
+    pub fn id(&self) -> Hash {
+        sha256_128(&self.encode())
+    }
+}
+
+/// Id of a batch, hashed over the encodings of its transactions in nonce
+/// order, without their batch references.
+pub fn batch_id(transactions: &[Transaction]) -> Hash {
+    let mut data = Vec::new();
+    for transaction in transactions {
+        let mut transaction = transaction.clone();
+        transaction.batch = None;
+        data.extend_from_slice(&transaction.encode());
+    }
+    sha256_128(&data)
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub height: u64,
+    pub prev_hash: Hash,
+    pub merkle_root: Hash,
+    pub timestamp: u64,
+    pub difficulty: u32,
+    #[serde(default)]
+    pub target: Hash,
+}
+
+impl BlockHeader {
+    /// Canonical header serialization hashed for proof of work, selected by
+    /// `version`. Fields are little-endian and written as one buffer, because
+    /// `RapidHasher` output depends on how the input is split across writes.
+    ///
+    /// * version 1: version, prev_hash, merkle_root, timestamp, nonce
+    /// * version 2: version, height, prev_hash, merkle_root, timestamp,
+    ///   difficulty, nonce
+    /// * version 3: version 2 with the target before the nonce
+    pub fn encode(&self, nonce: u128) -> Vec<u8> {
+        let mut data = Vec::with_capacity(88);
+        data.extend_from_slice(&self.version.to_le_bytes());
+        if self.version < 2 {
+            data.extend_from_slice(&self.prev_hash.to_le_bytes());
+            data.extend_from_slice(&self.merkle_root.to_le_bytes());
+            data.extend_from_slice(&self.timestamp.to_le_bytes());
+        } else {
+            data.extend_from_slice(&self.height.to_le_bytes());
+            data.extend_from_slice(&self.prev_hash.to_le_bytes());
+            data.extend_from_slice(&self.merkle_root.to_le_bytes());
+            data.extend_from_slice(&self.timestamp.to_le_bytes());
+            data.extend_from_slice(&self.difficulty.to_le_bytes());
+            if self.version >= 3 {
+                data.extend_from_slice(&self.target.to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&nonce.to_le_bytes());
+        data
+    }
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+    pub nonce: u128,
+    pub hash: Hash,
+}
+
+impl Block {
+    pub fn new(
+        prev_block: &Block,
+        transactions: Vec<Transaction>,
+        target: Hash,
+        timestamp: u64
+    ) -> Result<Self, String> {
+        let merkle_root = Block::calculate_merkle_root(&transactions, BLOCK_VERSION);
+        let target = normalize_target(target);
+
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            height: prev_block.header.height + 1,
+            prev_hash: prev_block.hash,
+            merkle_root,
+            timestamp,
+            difficulty: target.leading_zeros(),
+            target,
+        };
+
+        let mut block = Self {
+            header,
+            transactions,
+            nonce: 0,
+            hash: 0,
+        };
+
+        block.calculate_block_hash();
+
+        Ok(block)
+    }
+
+    /// Merkle leaf of `transaction` in a block of `version`. Transfers with a
+    /// nonce, and coinbases since version 4, are hashed by their id; older
+    /// transactions keep the text encoding they were mined with, which leaves
+    /// out the timestamp of transfers and the subaccount of coinbases.
+    pub fn hash_transaction(transaction: &Transaction, version: u32) -> Hash {
+        if transaction.nonce > 0 || (transaction.is_coinbase() && version >= 4) {
+            return transaction.id();
+        }
+
+        let tx_string = match transaction.kind {
+            TransactionKind::Transfer if transaction.fee == 0 =>
+                format!("{}{}{}", transaction.sender, transaction.recipient, transaction.amount),
+            TransactionKind::Transfer =>
+                format!(
+                    "{}{}{}fee{}",
+                    transaction.sender,
+                    transaction.recipient,
+                    transaction.amount,
+                    transaction.fee
+                ),
+            TransactionKind::Coinbase =>
+                format!(
+                    "coinbase{}{}{}",
+                    transaction.recipient,
+                    transaction.amount,
+                    transaction.timestamp
+                ),
+        };
+
+        hash128(tx_string.as_bytes())
+    }
+
+    pub fn calculate_merkle_root(transactions: &[Transaction], version: u32) -> Hash {
+        if transactions.is_empty() {
+            return 0;
+        }
+
+        let mut hashes: Vec<Hash> = transactions
+            .iter()
+            .map(|tx| Block::hash_transaction(tx, version))
+            .collect();
+
+        while hashes.len() > 1 {
+            if !hashes.len().is_multiple_of(2) {
+                hashes.push(*hashes.last().unwrap());
+            }
+
+            hashes = hashes
+                .chunks(2)
+                .map(|chunk| {
+                    let mut hasher = RapidHasher::new(0);
+                    hasher.write(&chunk[0].to_le_bytes());
+                    hasher.write(&chunk[1].to_le_bytes());
+                    widen(hasher.finish())
+                })
+                .collect();
+        }
+
+        hashes[0]
+    }
+
+    /// Fees paid by the transactions of the block, which the coinbase
+    /// collects on top of the reward.
+    pub fn fees(&self) -> u64 {
+        self.transactions
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.fee)
+            .sum()
+    }
+
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.first().filter(|tx| tx.is_coinbase())
+    }
+
+    /// Pays the coinbase of a template to `recipient`. Miners do this before
+    /// hashing, since the recipient is covered by the merkle root.
+    pub fn set_coinbase_recipient(&mut self, recipient: Principal) {
+        if let Some(coinbase) = self.transactions.first_mut().filter(|tx| tx.is_coinbase()) {
+            coinbase.recipient = recipient;
+            self.header.merkle_root = Block::calculate_merkle_root(
+                &self.transactions,
+                self.header.version
+            );
+        }
+    }
+
+    /// Proof-of-work hash of the header and nonce. Blocks since version 2
+    /// store this value in `hash`, so `prev_hash` commits to the solved work.
+    pub fn calculate_hash(&self) -> Hash {
+        hash128(&self.header.encode(self.nonce))
+    }
+
+    pub fn calculate_block_hash(&mut self) {
+        self.hash = self.calculate_hash();
+    }
+
+    /// Whether `hash` carries enough work for this block under the rule of
+    /// its header version.
+    pub fn meets_target(&self, hash: Hash) -> bool {
+        if self.header.version >= 3 {
+            hash <= self.header.target
+        } else {
+            hash.leading_zeros() >= self.header.difficulty
+        }
+    }
+
+    pub fn genesis() -> Self {
+        Self {
+            header: BlockHeader {
+                version: 1,
+                height: 0,
+                prev_hash: 0,
+                merkle_root: 0,
+                timestamp: 0,
+                difficulty: 0,
+                target: 0,
+            },
+            transactions: vec![],
+            nonce: 0,
+            hash: 0,
+        }
+    }
+}
+
+/// Mining telemetry reported by a miner with its solution. It is not
+/// verified and plays no part in validation or difficulty adjustment.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub cycles_burned: u64,
+    pub timestamp: u64,
+    pub solve_time: u64,
+    pub miner: Principal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compact::{ bits_to_target, normalize_target, target_to_bits };
+    use crate::testing::{ mine, owner, template };
+    use crate::vectors::{ compact_vectors, header_vectors, merkle_vectors, transaction_vectors };
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn block_with(header: BlockHeader, nonce: u128) -> Block {
+        Block {
+            header,
+            transactions: vec![],
+            nonce,
+            hash: 0,
+        }
+    }
+
+    #[test]
+    fn header_vectors_match() {
+        for v in header_vectors() {
+            assert_eq!(hex(&v.header.encode(v.nonce)), v.encoding);
+            assert_eq!(block_with(v.header, v.nonce).calculate_hash(), v.hash);
+        }
+    }
+
+    #[test]
+    fn transaction_vectors_match() {
+        for v in transaction_vectors() {
+            assert_eq!(hex(&v.transaction.encode()), v.encoding);
+            assert_eq!(v.transaction.id(), v.id);
+        }
+    }
+
+    #[test]
+    fn merkle_vectors_match() {
+        for v in merkle_vectors() {
+            assert_eq!(Block::calculate_merkle_root(&v.transactions, v.version), v.merkle_root);
+        }
+    }
+
+    #[test]
+    fn compact_vectors_match() {
+        for v in compact_vectors() {
+            assert_eq!(target_to_bits(v.target), v.bits);
+            assert_eq!(bits_to_target(v.bits), v.expanded);
+        }
+    }
+
+    #[test]
+    fn compact_round_trips() {
+        for shift in 0..128 {
+            let targets = [1_u128 << shift, Hash::MAX >> shift, 0x0123_4567_89ab_cdef << shift];
+            for target in targets {
+                let normalized = normalize_target(target);
+                assert!(normalized <= target);
+                let bits = target_to_bits(normalized);
+                assert_eq!(bits_to_target(bits), normalized);
+                assert_eq!(target_to_bits(bits_to_target(bits)), bits);
+            }
+        }
+    }
+
+    #[test]
+    fn mined_template_matches_known_hash() {
+        let block = mine(&template(), owner());
+
+        assert_eq!(block.coinbase().unwrap().recipient, owner());
+        assert_eq!(block.header.merkle_root, 0x2d002b99bc1898d0bca073933ed6aa2a);
+        assert_eq!(block.nonce, 30);
+        assert_eq!(block.hash, 0x01312e671fbb663609fb61ba90727ab7);
+    }
+}
@@ -0,0 +1,32 @@
+//! Fixtures for tests of this crate and of crates mining or validating its
+//! blocks, behind the `test-util` feature.
+
+use candid::Principal;
+use crate::{ Block, Hash, Transaction };
+
+/// Owner the fixture blocks are mined for.
+pub fn owner() -> Principal {
+    Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+}
+
+/// Template on top of genesis with an unclaimed coinbase of 600 and a target
+/// a test mines in a few nonces.
+pub fn template() -> Block {
+    let coinbase = Transaction::coinbase(Principal::anonymous(), 600, 1_700_000_000);
+    Block::new(&Block::genesis(), vec![coinbase], Hash::MAX >> 4, 1_700_000_000).unwrap()
+}
+
+/// Mines `template` the way the miner does: pays the coinbase to `owner`,
+/// then tries nonces from zero until the hash meets the target.
+pub fn mine(template: &Block, owner: Principal) -> Block {
+    let mut block = template.clone();
+    block.set_coinbase_recipient(owner);
+    block.hash = (0..)
+        .find_map(|nonce| {
+            block.nonce = nonce;
+            let hash = block.calculate_hash();
+            block.meets_target(hash).then_some(hash)
+        })
+        .unwrap();
+    block
+}
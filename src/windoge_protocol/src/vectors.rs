@@ -0,0 +1,339 @@
+//! Reference encodings and hashes for miners and tooling that hash blocks
+//! or decode targets outside of this crate. A change that alters any of these values changes
+//! consensus and needs a new `BLOCK_VERSION`.
+
+use candid::Principal;
+use serde_bytes::ByteBuf;
+use crate::{ BatchRef, BlockHeader, Hash, Transaction, TransactionKind };
+
+pub struct HeaderVector {
+    pub header: BlockHeader,
+    pub nonce: u128,
+    /// Hex of `BlockHeader::encode(nonce)`.
+    pub encoding: &'static str,
+    /// `Block::calculate_hash` of a block with this header and nonce.
+    pub hash: Hash,
+}
+
+pub struct CompactVector {
+    pub target: Hash,
+    /// `compact::target_to_bits(target)`.
+    pub bits: u32,
+    /// `compact::bits_to_target(bits)`.
+    pub expanded: Hash,
+}
+
+pub struct TransactionVector {
+    pub transaction: Transaction,
+    /// Hex of `Transaction::encode`.
+    pub encoding: &'static str,
+    /// `Transaction::id`.
+    pub id: Hash,
+}
+
+pub struct MerkleVector {
+    /// Header version of the block holding `transactions`.
+    pub version: u32,
+    pub transactions: Vec<Transaction>,
+    pub merkle_root: Hash,
+}
+
+pub fn header_vectors() -> Vec<HeaderVector> {
+    vec![
+        HeaderVector {
+            header: BlockHeader {
+                version: 2,
+                height: 1,
+                prev_hash: 0,
+                merkle_root: 0,
+                timestamp: 1_700_000_000_000_000_000,
+                difficulty: 26,
+                target: 0,
+            },
+            nonce: 0,
+            encoding: "0200000001000000000000000000000000000000000000000000000000000000\
+                       00000000000000000000000000002a36fe9c97171a0000000000000000000000\
+                       0000000000000000",
+            hash: 0xe623b095e30bce203863977fb3b84ee5,
+        },
+        HeaderVector {
+            header: BlockHeader {
+                version: 2,
+                height: 42,
+                prev_hash: 0x0123456789abcdef0123456789abcdef,
+                merkle_root: 0xfedcba9876543210fedcba9876543210,
+                timestamp: 1_735_689_600_000_000_000,
+                difficulty: 30,
+                target: 0,
+            },
+            nonce: 0xdeadbeefcafebabe0123456789abcdef,
+            encoding: "020000002a00000000000000efcdab8967452301efcdab896745230110325476\
+                       98badcfe1032547698badcfe000057c07e6816181e000000efcdab8967452301\
+                       bebafecaefbeadde",
+            hash: 0x0e88fa6413ca0dc192152e21e3371cb4,
+        },
+        HeaderVector {
+            header: BlockHeader {
+                version: 1,
+                height: 7,
+                prev_hash: 0x0123456789abcdef0123456789abcdef,
+                merkle_root: 0xfedcba9876543210fedcba9876543210,
+                timestamp: 1_735_689_600_000_000_000,
+                difficulty: 28,
+                target: 0,
+            },
+            nonce: 0xdeadbeefcafebabe0123456789abcdef,
+            encoding: "01000000efcdab8967452301efcdab89674523011032547698badcfe10325476\
+                       98badcfe000057c07e681618efcdab8967452301bebafecaefbeadde",
+            hash: 0x9fc3e859fd0f3bef8f0be32bee7e0de0,
+        },
+        HeaderVector {
+            header: BlockHeader {
+                version: 3,
+                height: 1000,
+                prev_hash: 0x0123456789abcdef0123456789abcdef,
+                merkle_root: 0xfedcba9876543210fedcba9876543210,
+                timestamp: 1_735_689_600_000_000_000,
+                difficulty: 26,
+                target: 0x0000003fffff00000000000000000000,
+            },
+            nonce: 0xdeadbeefcafebabe0123456789abcdef,
+            encoding: "03000000e803000000000000efcdab8967452301efcdab896745230110325476\
+                       98badcfe1032547698badcfe000057c07e6816181a0000000000000000000000\
+                       0000ffff3f000000efcdab8967452301bebafecaefbeadde",
+            hash: 0x2803d8ed3059e9b839780d91d20c61d9,
+        }
+    ]
+}
+
+pub fn compact_vectors() -> Vec<CompactVector> {
+    vec![
+        CompactVector { target: 0x1, bits: 0x01010000, expanded: 0x1 },
+        CompactVector { target: 0x7f, bits: 0x017f0000, expanded: 0x7f },
+        CompactVector { target: 0x80, bits: 0x02008000, expanded: 0x80 },
+        CompactVector { target: 0x12345678, bits: 0x04123456, expanded: 0x12345600 },
+        CompactVector {
+            target: 0x3fffffffffffffffffffffffff,
+            bits: 0x0d3fffff,
+            expanded: 0x3fffff00000000000000000000,
+        },
+        CompactVector {
+            target: Hash::MAX,
+            bits: 0x1100ffff,
+            expanded: 0xffff0000000000000000000000000000,
+        }
+    ]
+}
+
+pub fn transaction_vectors() -> Vec<TransactionVector> {
+    let a = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+    let b = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+    vec![
+        TransactionVector {
+            transaction: Transaction {
+                sender: a,
+                recipient: b,
+                amount: 100_000_000,
+                timestamp: 1_735_689_603_000_000_000,
+                kind: TransactionKind::Transfer,
+                fee: 10_000,
+                nonce: 7,
+                batch: None,
+                memo: None,
+                from_subaccount: None,
+                to_subaccount: None,
+            },
+            encoding: "000a000000000000000101010a0000000000000002010100e1f5050000000010\
+                       270000000000000700000000000000005e27737f681618",
+            id: 0x2c56126be0d15f980e8f3ca387f2a11b,
+        },
+        TransactionVector {
+            transaction: Transaction {
+                sender: a,
+                recipient: b,
+                amount: 100_000_000,
+                timestamp: 1_735_689_603_000_000_000,
+                kind: TransactionKind::Transfer,
+                fee: 10_000,
+                nonce: 8,
+                batch: Some(BatchRef {
+                    id: 0x0123456789abcdef0123456789abcdef,
+                    size: 2,
+                }),
+                memo: None,
+                from_subaccount: None,
+                to_subaccount: None,
+            },
+            encoding: "000a000000000000000101010a0000000000000002010100e1f5050000000010\
+                       270000000000000800000000000000005e27737f68161801efcdab8967452301\
+                       efcdab896745230102000000",
+            id: 0x37cf80b07743a800bf744e41add97e5f,
+        },
+        TransactionVector {
+            transaction: Transaction {
+                sender: b,
+                recipient: a,
+                amount: 250_000_000,
+                timestamp: 1_735_689_604_000_000_000,
+                kind: TransactionKind::Transfer,
+                fee: 0,
+                nonce: 1,
+                batch: None,
+                memo: Some(ByteBuf::from(b"invoice-42".to_vec())),
+                from_subaccount: None,
+                to_subaccount: None,
+            },
+            encoding: "000a000000000000000201010a0000000000000001010180b2e60e0000000000\
+                       0000000000000001000000000000000028c2ae7f681618020a696e766f696365\
+                       2d3432",
+            id: 0xb59ab63c93149d040bd06da5ab168f70,
+        },
+        TransactionVector {
+            transaction: Transaction {
+                sender: a,
+                recipient: b,
+                amount: 40_000_000,
+                timestamp: 1_735_689_605_000_000_000,
+                kind: TransactionKind::Transfer,
+                fee: 10_000,
+                nonce: 9,
+                batch: None,
+                memo: None,
+                from_subaccount: Some(ByteBuf::from([1; 32].to_vec())),
+                to_subaccount: Some(ByteBuf::from([2; 32].to_vec())),
+            },
+            encoding: "000a000000000000000101010a00000000000000020101005a62020000000010\
+                       27000000000000090000000000000000f25cea7f681618032001010101010101\
+                       0101010101010101010101010101010101010101010101010104200202020202\
+                       020202020202020202020202020202020202020202020202020202",
+            id: 0x9276e7b9d8f42aa02e95f6703528d024,
+        },
+        TransactionVector {
+            transaction: Transaction::coinbase(b, 5_000_000_000, 1_735_689_600_000_000_000),
+            encoding: "01000a0000000000000002010100f2052a010000000000000000000000000000\
+                       0000000000000057c07e681618",
+            id: 0x41a8ed0c6345d9e79f69e07a0d282035,
+        }
+    ]
+}
+
+pub fn merkle_vectors() -> Vec<MerkleVector> {
+    let a = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+    let b = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+    let first = Transaction {
+        sender: a,
+        recipient: b,
+        amount: 100_000_000,
+        timestamp: 1_735_689_600_000_000_000,
+        kind: TransactionKind::Transfer,
+        fee: 0,
+        nonce: 0,
+        batch: None,
+        memo: None,
+        from_subaccount: None,
+        to_subaccount: None,
+    };
+    let second = Transaction {
+        sender: b,
+        recipient: a,
+        amount: 42,
+        timestamp: 1_735_689_601_000_000_000,
+        kind: TransactionKind::Transfer,
+        fee: 0,
+        nonce: 0,
+        batch: None,
+        memo: None,
+        from_subaccount: None,
+        to_subaccount: None,
+    };
+    let third = Transaction {
+        sender: a,
+        recipient: a,
+        amount: 1,
+        timestamp: 1_735_689_602_000_000_000,
+        kind: TransactionKind::Transfer,
+        fee: 0,
+        nonce: 0,
+        batch: None,
+        memo: None,
+        from_subaccount: None,
+        to_subaccount: None,
+    };
+
+    let with_fee = Transaction {
+        sender: b,
+        recipient: a,
+        amount: 42,
+        timestamp: 1_735_689_601_000_000_000,
+        kind: TransactionKind::Transfer,
+        fee: 10_000,
+        nonce: 0,
+        batch: None,
+        memo: None,
+        from_subaccount: None,
+        to_subaccount: None,
+    };
+    let with_nonce = Transaction {
+        sender: a,
+        recipient: b,
+        amount: 100_000_000,
+        timestamp: 1_735_689_603_000_000_000,
+        kind: TransactionKind::Transfer,
+        fee: 10_000,
+        nonce: 7,
+        batch: None,
+        memo: None,
+        from_subaccount: None,
+        to_subaccount: None,
+    };
+    let coinbase = Transaction::coinbase(a, 5_000_000_000, 1_735_689_600_000_000_000);
+    let to_subaccount = Transaction {
+        to_subaccount: Some(ByteBuf::from(vec![1; 32])),
+        ..coinbase.clone()
+    };
+
+    vec![
+        MerkleVector {
+            version: 3,
+            transactions: vec![],
+            merkle_root: 0,
+        },
+        MerkleVector {
+            version: 3,
+            transactions: vec![first.clone()],
+            merkle_root: 0x19b628f91f4f4d4fdc705131dd47a5f9,
+        },
+        MerkleVector {
+            version: 3,
+            transactions: vec![first.clone(), second, third],
+            merkle_root: 0x0094a071d03dc11b8be958a417b8886c,
+        },
+        MerkleVector {
+            version: 3,
+            transactions: vec![coinbase.clone(), first.clone()],
+            merkle_root: 0xc267f6a0ab0484a22daeb14ad556b783,
+        },
+        MerkleVector {
+            version: 4,
+            transactions: vec![coinbase, first.clone()],
+            merkle_root: 0xf95f77f7e3a731069aba6873f662fb97,
+        },
+        MerkleVector {
+            version: 4,
+            transactions: vec![to_subaccount, first],
+            merkle_root: 0x71016e6792d26c33637464cf5c19fefb,
+        },
+        MerkleVector {
+            version: 3,
+            transactions: vec![with_fee],
+            merkle_root: 0x9829abe649be6d89d5a1ee312d469559,
+        },
+        MerkleVector {
+            version: 3,
+            transactions: vec![with_nonce],
+            merkle_root: 0x2c56126be0d15f980e8f3ca387f2a11b,
+        }
+    ]
+}